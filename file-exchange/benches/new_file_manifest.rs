@@ -11,6 +11,7 @@ fn new_file_manifest_benchmark_object_store(c: &mut Criterion) {
     let store = black_box(
         Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: "../example-file".to_string(),
+            ..Default::default()
         }))
         .unwrap(),
     );
@@ -12,6 +12,7 @@ fn read_and_validate_file_benchmark(c: &mut Criterion) {
     let store = black_box(
         Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: "../example-file".to_string(),
+            ..Default::default()
         }))
         .unwrap(),
     );
@@ -5,6 +5,7 @@ use file_exchange::{
     config::{LocalDirectory, StorageMethod},
     manifest::store::Store,
 };
+use object_store::path::Path;
 use rand::Rng;
 use std::{fs::File, ops::Range, path::PathBuf};
 
@@ -20,6 +21,7 @@ fn read_chunk_benchmark(c: &mut Criterion) {
     let store = black_box(
         Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: "../example-file".to_string(),
+            ..Default::default()
         }))
         .unwrap(),
     );
@@ -36,7 +38,7 @@ fn read_chunk_benchmark(c: &mut Criterion) {
     c.bench_function("read_chunk", |b| {
         let range = black_box(random_file_range(file_size));
         b.to_async(FuturesExecutor)
-            .iter(|| store.range_read(file_name, &range))
+            .iter(|| store.range_read(file_name, &Path::from(""), &range))
     });
 }
 
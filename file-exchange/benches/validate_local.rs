@@ -15,6 +15,7 @@ fn validate_local_bundle_benchmark(c: &mut Criterion) {
     let store = black_box(
         Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: "../example-file".to_string(),
+            ..Default::default()
         }))
         .unwrap(),
     );
@@ -53,6 +53,7 @@ impl Cli {
 #[group(required = false, multiple = true)]
 pub enum Role {
     Downloader(DownloaderArgs),
+    Discover(DiscoverArgs),
     Publisher(PublisherArgs),
     Wallet(OnChainArgs),
 }
@@ -105,6 +106,9 @@ pub struct LocalDirectory {
         help = "Output directory for the downloaded files"
     )]
     pub main_dir: String,
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub store_options: StoreOptions,
 }
 
 #[derive(Clone, Debug, Args, Serialize, Deserialize, Default)]
@@ -146,6 +150,87 @@ pub struct ObjectStoreArgs {
         help = "Endpoint to the bucket (ex. https://ams3.digitaloceanspaces.com"
     )]
     pub endpoint: String,
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub store_options: StoreOptions,
+}
+
+/// Read/write concurrency, multipart sizing, and request timeouts shared by all storage methods
+#[derive(Clone, Debug, Args, Serialize, Deserialize)]
+#[group(required = false, multiple = true)]
+#[serde(default)]
+pub struct StoreOptions {
+    #[clap(
+        long,
+        value_name = "read_concurrency",
+        env = "STORE_READ_CONCURRENCY",
+        default_value = "16",
+        help = "Number of concurrent range reads when reading an object in parts"
+    )]
+    pub read_concurrency: usize,
+    #[clap(
+        long,
+        value_name = "write_concurrency",
+        env = "STORE_WRITE_CONCURRENCY",
+        default_value = "8",
+        help = "Number of concurrent part uploads when writing an object in parts"
+    )]
+    pub write_concurrency: usize,
+    #[clap(
+        long,
+        value_name = "min_part_size",
+        env = "STORE_MIN_PART_SIZE",
+        default_value = "5242880",
+        help = "Minimum part size in bytes for multipart reads and writes (S3 requires at least 5MiB for all but the last part)"
+    )]
+    pub min_part_size: usize,
+    #[clap(
+        long,
+        value_name = "max_part_size",
+        env = "STORE_MAX_PART_SIZE",
+        default_value = "536870912",
+        help = "Maximum part size in bytes for multipart reads and writes (S3 allows at most 5GiB per part)"
+    )]
+    pub max_part_size: usize,
+    #[clap(
+        long,
+        value_name = "request_timeout",
+        env = "STORE_REQUEST_TIMEOUT",
+        default_value = "30",
+        help = "Timeout in seconds for a single request to the object store"
+    )]
+    pub request_timeout: u64,
+    #[clap(
+        long,
+        value_name = "connect_timeout",
+        env = "STORE_CONNECT_TIMEOUT",
+        default_value = "5",
+        help = "Timeout in seconds for establishing a connection to the object store"
+    )]
+    pub connect_timeout: u64,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            read_concurrency: 16,
+            write_concurrency: 8,
+            min_part_size: 5 * 1024 * 1024,
+            max_part_size: 512 * 1024 * 1024,
+            request_timeout: 30,
+            connect_timeout: 5,
+        }
+    }
+}
+
+impl StorageMethod {
+    /// Store tuning options of the configured storage method
+    pub fn store_options(&self) -> &StoreOptions {
+        match self {
+            StorageMethod::LocalFiles(directory) => &directory.store_options,
+            StorageMethod::ObjectStorage(store) => &store.store_options,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Args, Serialize, Deserialize, Default)]
@@ -236,9 +321,47 @@ pub struct DownloaderArgs {
         value_name = "INDEXER_ENDPOINTS",
         value_delimiter = ',',
         env = "INDEXER_ENDPOINTS",
-        help = "A list of indexer endpoints to query data from"
+        help = "A list of indexer endpoints to query data from, added to the endpoints of indexers allocated to the bundle in the network subgraph; overrides a discovered endpoint of the same operator"
     )]
     pub indexer_endpoints: Vec<String>,
+    #[arg(
+        long,
+        env = "MANUAL_ENDPOINTS_ONLY",
+        help = "Only query the configured indexer endpoints, skipping endpoint discovery from the network subgraph"
+    )]
+    pub manual_endpoints_only: bool,
+    #[arg(
+        long,
+        value_name = "INCLUDE",
+        value_delimiter = ',',
+        env = "INCLUDE",
+        help = "Only download the files of the bundle with these names or glob patterns (ex. 'users.sql,*.csv'); all files by default"
+    )]
+    pub include: Vec<String>,
+    #[arg(
+        long,
+        value_name = "EXCLUDE",
+        value_delimiter = ',',
+        env = "EXCLUDE",
+        help = "Skip the files of the bundle with these names or glob patterns"
+    )]
+    pub exclude: Vec<String>,
+    #[arg(
+        long,
+        value_name = "DISCOVERY_CONCURRENCY",
+        default_value = "16",
+        env = "DISCOVERY_CONCURRENCY",
+        help = "Maximum number of indexer endpoints queried at once during discovery"
+    )]
+    pub discovery_concurrency: usize,
+    #[arg(
+        long,
+        value_name = "STATUS_CACHE_TTL",
+        default_value = "60",
+        env = "STATUS_CACHE_TTL",
+        help = "Seconds the bundles served by an indexer endpoint and its operator are cached during discovery"
+    )]
+    pub status_cache_ttl: u64,
     #[clap(subcommand)]
     pub storage_method: StorageMethod,
     #[clap(
@@ -262,6 +385,14 @@ pub struct DownloaderArgs {
         help = "The Graph Scalar TAP Subgraph API endpoint"
     )]
     pub escrow_subgraph: String,
+    #[arg(
+        long,
+        value_name = "ALLOCATION_CACHE_TTL",
+        default_value = "300",
+        env = "ALLOCATION_CACHE_TTL",
+        help = "Seconds a provider's active allocation, looked up in the network subgraph for receipts, is cached before checking whether it closed"
+    )]
+    pub allocation_cache_ttl: u64,
 
     #[arg(
         long,
@@ -271,6 +402,30 @@ pub struct DownloaderArgs {
         help = "Maximum retry for each chunk"
     )]
     pub max_retry: u64,
+    #[arg(
+        long,
+        value_name = "CHUNK_TIMEOUT",
+        default_value = "30",
+        env = "CHUNK_TIMEOUT",
+        help = "Seconds before a single chunk request times out and is retried"
+    )]
+    pub chunk_timeout: u64,
+    #[arg(
+        long,
+        value_name = "RETRY_BASE_DELAY_MS",
+        default_value = "500",
+        env = "RETRY_BASE_DELAY_MS",
+        help = "Milliseconds before the first retry of a chunk request; doubles with each attempt, randomized with jitter"
+    )]
+    pub retry_base_delay_ms: u64,
+    #[arg(
+        long,
+        value_name = "RETRY_MAX_DELAY_MS",
+        default_value = "30000",
+        env = "RETRY_MAX_DELAY_MS",
+        help = "Maximum milliseconds between retries of a chunk request; longer Retry-After requests move the chunk to another provider"
+    )]
+    pub retry_max_delay_ms: u64,
     #[arg(
         long,
         value_name = "PROVIDER_CONCURRENCY",
@@ -279,6 +434,94 @@ pub struct DownloaderArgs {
         help = "Configure maximum concurrency limit for downloading the bundle from; affects cost estimation for escrow accounts, transfer speed performance, failure rate"
     )]
     pub provider_concurrency: u64,
+    #[arg(
+        long,
+        value_name = "CHUNK_CONCURRENCY",
+        default_value = "64",
+        env = "CHUNK_CONCURRENCY",
+        help = "Maximum number of chunk requests in flight across all providers"
+    )]
+    pub chunk_concurrency: usize,
+    #[arg(
+        long,
+        value_name = "PROVIDER_CHUNK_CONCURRENCY",
+        default_value = "8",
+        env = "PROVIDER_CHUNK_CONCURRENCY",
+        help = "Maximum number of chunk requests in flight to a single provider"
+    )]
+    pub provider_chunk_concurrency: usize,
+    #[arg(
+        long,
+        value_name = "CHUNKS_PER_REQUEST",
+        default_value = "4",
+        env = "CHUNKS_PER_REQUEST",
+        help = "Maximum number of contiguous chunks requested from a provider in one range request; each chunk is verified on arrival"
+    )]
+    pub chunks_per_request: usize,
+    #[arg(
+        long,
+        value_name = "MAX_DOWNLOAD_RATE",
+        default_value = "0",
+        env = "MAX_DOWNLOAD_RATE",
+        help = "Maximum download rate in bytes per second across all providers; 0 for no limit"
+    )]
+    pub max_download_rate: u64,
+    #[arg(
+        long,
+        value_name = "MAX_PROVIDER_DOWNLOAD_RATE",
+        default_value = "0",
+        env = "MAX_PROVIDER_DOWNLOAD_RATE",
+        help = "Maximum download rate in bytes per second from a single provider; 0 for no limit"
+    )]
+    pub max_provider_download_rate: u64,
+    #[arg(
+        long,
+        value_name = "SELECTION_POLICY",
+        value_enum,
+        default_value = "balanced",
+        env = "SELECTION_POLICY",
+        help = "Policy weighting chunk assignment across providers: cheapest (price), fastest (measured throughput), or balanced (throughput per price); all weighted by reliability"
+    )]
+    pub selection_policy: SelectionPolicy,
+    #[arg(
+        long,
+        value_name = "HEDGE_PERCENTILE",
+        env = "HEDGE_PERCENTILE",
+        help = "Send a duplicate request for a chunk to a second provider once it runs longer than this percentile of recent chunk latencies (ex. 95); disabled by default"
+    )]
+    pub hedge_percentile: Option<f64>,
+    #[arg(
+        long,
+        value_name = "HEDGE_BUDGET",
+        default_value = "5",
+        env = "HEDGE_BUDGET",
+        help = "Maximum extra bytes requested by hedged requests, as a percentage of the bytes to download"
+    )]
+    pub hedge_budget: f64,
+    #[arg(
+        long,
+        value_name = "BLOCKLIST_COOLDOWN",
+        default_value = "5",
+        env = "BLOCKLIST_COOLDOWN",
+        help = "Seconds a provider is excluded after its first transient failure; doubles with each consecutive failure"
+    )]
+    pub blocklist_cooldown: u64,
+    #[arg(
+        long,
+        value_name = "BLOCKLIST_MAX_COOLDOWN",
+        default_value = "300",
+        env = "BLOCKLIST_MAX_COOLDOWN",
+        help = "Maximum seconds a provider is excluded after consecutive transient failures"
+    )]
+    pub blocklist_max_cooldown: u64,
+    #[arg(
+        long,
+        value_name = "BLOCKLIST_MAX_FAILURES",
+        default_value = "8",
+        env = "BLOCKLIST_MAX_FAILURES",
+        help = "Consecutive transient failures after which a provider is banned for the rest of the download"
+    )]
+    pub blocklist_max_failures: u32,
     #[arg(
         long,
         value_name = "MAXIMUM_AUTO_DEPOSIT",
@@ -287,6 +530,14 @@ pub struct DownloaderArgs {
         help = "Maximum GRT configured for automatic deposit (Used for GraphToken approval to Escrow contract and across Escrow accounts"
     )]
     pub max_auto_deposit: f64,
+    #[arg(
+        long,
+        value_name = "MAX_SPEND",
+        default_value = "0",
+        env = "MAX_SPEND",
+        help = "Maximum GRT of receipts issued for the download, including previous runs recorded in the progress file (0 for no limit); the download stops once reached"
+    )]
+    pub max_spend: f64,
     #[clap(
         long,
         value_name = "PROGRESS_CACHE",
@@ -294,6 +545,41 @@ pub struct DownloaderArgs {
         help = "Json file to store progress if download fails; read the file to resume download if the file is nonempty"
     )]
     pub progress_file: Option<String>,
+    #[arg(
+        long,
+        env = "VERIFY_EXISTING",
+        help = "Resume by hashing existing partial or output files against the chunk hashes instead of trusting recorded progress"
+    )]
+    pub verify_existing: bool,
+}
+
+/// Discover rank the bundles served by indexers that overlap a target bundle
+#[derive(Clone, Debug, Args, Serialize, Deserialize, Default)]
+#[group(required = false, multiple = true)]
+pub struct DiscoverArgs {
+    #[arg(
+        long,
+        value_name = "IPFS_HASH",
+        env = "IPFS_HASH",
+        help = "IPFS hash for the target bundle.yaml"
+    )]
+    pub ipfs_hash: String,
+    #[arg(
+        long,
+        value_name = "INDEXER_ENDPOINTS",
+        value_delimiter = ',',
+        env = "INDEXER_ENDPOINTS",
+        help = "A list of indexer endpoints to discover bundles from"
+    )]
+    pub indexer_endpoints: Vec<String>,
+    #[arg(
+        long,
+        value_name = "DISCOVERY_CONCURRENCY",
+        default_value = "16",
+        env = "DISCOVERY_CONCURRENCY",
+        help = "Maximum number of indexer endpoints queried at once"
+    )]
+    pub discovery_concurrency: usize,
 }
 
 /// Publisher takes the files, generate bundle manifest, and publish to IPFS
@@ -560,6 +846,14 @@ pub fn init_tracing(format: &str) -> Result<(), SetGlobalDefaultError> {
     }
 }
 
+#[derive(clap::ValueEnum, Clone, Debug, Serialize, Deserialize, Default)]
+pub enum SelectionPolicy {
+    Cheapest,
+    Fastest,
+    #[default]
+    Balanced,
+}
+
 #[derive(clap::ValueEnum, Clone, Debug, Serialize, Deserialize, Default)]
 pub enum LogFormat {
     Compact,
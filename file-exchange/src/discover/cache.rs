@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+
+use crate::errors::Error;
+
+/// Values fetched at most once per key, shared by concurrent callers and kept until the TTL
+/// runs out; without a TTL values are kept for good, as for content addressed manifests.
+/// Failed fetches are not cached.
+#[derive(Debug)]
+pub struct FetchCache<V> {
+    ttl: Option<Duration>,
+    entries: StdMutex<HashMap<String, (Instant, Arc<OnceCell<V>>)>>,
+}
+
+impl<V: Clone> FetchCache<V> {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        FetchCache {
+            ttl,
+            entries: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the cached value of a key, or fetch it. Callers arriving while a fetch for the key
+    /// is in flight wait for its result instead of fetching again.
+    pub async fn get_or_fetch<F, Fut>(&self, key: &str, fetch: F) -> Result<V, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, Error>>,
+    {
+        let cell = {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get(key) {
+                Some((fetched, cell)) if self.ttl.map_or(true, |ttl| fetched.elapsed() < ttl) => {
+                    cell.clone()
+                }
+                _ => {
+                    let cell = Arc::new(OnceCell::new());
+                    entries.insert(key.to_string(), (Instant::now(), cell.clone()));
+                    cell
+                }
+            }
+        };
+        cell.get_or_try_init(fetch).await.cloned()
+    }
+
+    /// Drop the cached value of a key so the next call fetches it again
+    pub fn invalidate(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_fetches_are_deduplicated() {
+        let cache: FetchCache<u64> = FetchCache::new(None);
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(7)
+        };
+        let (a, b) = tokio::join!(
+            cache.get_or_fetch("a", fetch),
+            cache.get_or_fetch("a", fetch)
+        );
+        assert_eq!((a.unwrap(), b.unwrap()), (7, 7));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        cache.invalidate("a");
+        cache.get_or_fetch("a", fetch).await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_values_expire_and_errors_are_not_cached() {
+        let cache: FetchCache<u64> = FetchCache::new(Some(Duration::from_secs(60)));
+        let failed = cache
+            .get_or_fetch("a", || async {
+                Err(Error::DataUnavailable("unreachable".to_string()))
+            })
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(
+            cache.get_or_fetch("a", || async { Ok(1) }).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            cache.get_or_fetch("a", || async { Ok(2) }).await.unwrap(),
+            1
+        );
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(
+            cache.get_or_fetch("a", || async { Ok(2) }).await.unwrap(),
+            2
+        );
+    }
+}
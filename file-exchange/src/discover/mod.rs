@@ -1,21 +1,32 @@
 use futures::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::sync::Mutex;
 
 use crate::errors::Error;
 use crate::graphql::cost_query::indexer_bundle_cost;
+use crate::graphql::network_query::active_allocations;
 
 use crate::graphql::status_query::indexer_bundles;
 use crate::manifest::{
     ipfs::IpfsClient,
     manifest_fetcher::{fetch_bundle_from_ipfs, read_bundle},
+    Bundle, BundleManifest, FileMetaInfo,
 };
-use crate::util::{UDecimal18, GRT};
+
+use self::cache::FetchCache;
+
+pub mod cache;
+
+/// Endpoints queried at once during discovery
+pub const DEFAULT_DISCOVERY_CONCURRENCY: usize = 16;
+/// Time an endpoint's served bundles and operator are cached for
+pub const DEFAULT_STATUS_TTL: Duration = Duration::from_secs(60);
 
 // Pair indexer operator address and indexer service endpoint (operator, indexer_url)
 // persumeably this should not be handled by clients themselves
@@ -30,9 +41,30 @@ pub struct ServiceEndpoint {
 // Pair HashMap< FileManifestIPFS, HashMap< Service URL, Vec< MatchedManifestIPFS > > >
 pub type FileAvailbilityMap = Arc<Mutex<HashMap<String, Arc<Mutex<HashMap<String, Vec<String>>>>>>>;
 
+/// A served bundle overlapping a target bundle, with the share of the target it covers
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BundleCompatibility {
+    /// IPFS hash of the served bundle
+    pub bundle: String,
+    /// Files of the target bundle contained in the served bundle
+    pub matched_files: Vec<FileMetaInfo>,
+    /// Percentage of the target bundle's files contained
+    pub file_percentage: f64,
+    /// Percentage of the target bundle's bytes contained
+    pub byte_percentage: f64,
+    /// Providers of the served bundle and their prices, cheapest first
+    pub providers: Vec<ServiceEndpoint>,
+}
+
 pub struct Finder {
     ipfs_client: IpfsClient,
     http_client: reqwest::Client,
+    concurrency: usize,
+    // Bundles served by each endpoint and its operator, refreshed after a TTL
+    statuses: FetchCache<Vec<String>>,
+    operators: FetchCache<String>,
+    // Bundle manifests are content addressed and fetched once across endpoints
+    manifests: FetchCache<Arc<BundleManifest>>,
 }
 
 impl Finder {
@@ -40,17 +72,77 @@ impl Finder {
         Finder {
             ipfs_client,
             http_client: reqwest::Client::new(),
+            concurrency: DEFAULT_DISCOVERY_CONCURRENCY,
+            statuses: FetchCache::new(Some(DEFAULT_STATUS_TTL)),
+            operators: FetchCache::new(Some(DEFAULT_STATUS_TTL)),
+            manifests: FetchCache::new(None),
         }
     }
 
+    /// Set the number of endpoints queried at once and how long endpoint statuses are cached
+    pub fn with_limits(mut self, concurrency: usize, status_ttl: Duration) -> Self {
+        self.concurrency = concurrency.max(1);
+        self.statuses = FetchCache::new(Some(status_ttl));
+        self.operators = FetchCache::new(Some(status_ttl));
+        self
+    }
+
+    /// Bundles served at an endpoint
+    pub async fn served_bundles(&self, url: &str) -> Result<Vec<String>, Error> {
+        self.statuses
+            .get_or_fetch(url, || indexer_bundles(&self.http_client, url))
+            .await
+    }
+
+    /// Bundle manifest of an IPFS hash
+    pub async fn bundle_manifest(&self, bundle_hash: &str) -> Result<Arc<BundleManifest>, Error> {
+        self.manifests
+            .get_or_fetch(bundle_hash, || async {
+                fetch_bundle_from_ipfs(&self.ipfs_client, bundle_hash)
+                    .await
+                    .map(Arc::new)
+            })
+            .await
+    }
+
+    /// Drop the cached status of an endpoint, such as after it failed to serve a request
+    pub fn invalidate_status(&self, url: &str) {
+        self.statuses.invalidate(url);
+        self.operators.invalidate(url);
+    }
+
+    /// Service URLs of the indexers with active allocations on the bundle, from the network
+    /// subgraph
+    pub async fn network_endpoints(
+        &self,
+        network_subgraph: &str,
+        bundle_hash: &str,
+    ) -> Result<Vec<String>, Error> {
+        let allocations =
+            active_allocations(&self.http_client, network_subgraph, bundle_hash).await?;
+        let urls = allocations
+            .into_iter()
+            .filter_map(|allocation| allocation.indexer.url)
+            .collect::<Vec<_>>();
+        tracing::debug!(
+            bundle_hash,
+            urls = tracing::field::debug(&urls),
+            "Indexer endpoints from network subgraph"
+        );
+        Ok(merge_endpoints(&[], &urls))
+    }
+
     /// Endpoint must serve operator info and the requested file
     async fn bundle_availability(
         &self,
         bundle_hash: &str,
         url: &str,
     ) -> Result<ServiceEndpoint, Error> {
-        let bundles = indexer_bundles(&self.http_client, url).await?;
-        let operator: String = self.indexer_operator(url).await?;
+        let bundles = self.served_bundles(url).await?;
+        let operator: String = self
+            .operators
+            .get_or_fetch(url, || self.indexer_operator(url))
+            .await?;
 
         tracing::debug!(
             url,
@@ -98,7 +190,7 @@ impl Finder {
         // Use a stream to process the endpoints in parallel
         let results = stream::iter(endpoint_checklist)
             .map(|url| self.bundle_availability(bundle_hash, url))
-            .buffer_unordered(endpoint_checklist.len()) // Parallelize up to the number of endpoints
+            .buffer_unordered(self.concurrency)
             .collect::<Vec<Result<ServiceEndpoint, Error>>>()
             .await;
 
@@ -119,30 +211,218 @@ impl Finder {
         endpoint_checklist: &[String],
     ) -> Result<FileAvailbilityMap, Error> {
         let bundle = read_bundle(&self.ipfs_client, bundle_hash).await?;
+        let file_hashes: Vec<String> = bundle
+            .file_manifests
+            .iter()
+            .map(|file_manifest| file_manifest.meta_info.hash.clone())
+            .collect();
+        self.files_discovery(&file_hashes, endpoint_checklist).await
+    }
+
+    /// Map each of the target files to the endpoints and bundles serving it
+    pub async fn files_discovery(
+        &self,
+        file_hashes: &[String],
+        endpoint_checklist: &[String],
+    ) -> Result<FileAvailbilityMap, Error> {
         // To fill in availability for each file, get a vector of (ServiceEndpoint, ManifestIPFS) that serves the file
         let target_hashes: FileAvailbilityMap = Arc::new(Mutex::new(
-            bundle
-                .file_manifests
+            file_hashes
                 .iter()
-                .map(|file_manifest| {
-                    (
-                        file_manifest.meta_info.hash.clone(),
-                        Arc::new(Mutex::new(HashMap::new())),
-                    )
-                })
+                .map(|hash| (hash.clone(), Arc::new(Mutex::new(HashMap::new()))))
                 .collect(),
         ));
 
-        for url in endpoint_checklist {
-            if let Err(_e) = self.file_availability(url, target_hashes.clone()).await {
-                tracing::debug!("Failed to get file availability: {:#?}", url);
-            };
-        }
+        stream::iter(endpoint_checklist)
+            .for_each_concurrent(self.concurrency, |url| {
+                let target_hashes = target_hashes.clone();
+                async move {
+                    if let Err(e) = self.file_availability(url, target_hashes).await {
+                        tracing::debug!(
+                            url,
+                            error = e.to_string(),
+                            "Failed to get file availability"
+                        );
+                    };
+                }
+            })
+            .await;
 
         tracing::info!("Discovered file availability map: {:#?}", target_hashes);
         Ok(target_hashes)
     }
 
+    /// Resolve a file availability map into the services to request each file from. Every
+    /// service endpoint is paired with the bundle it serves the file in, priced for that
+    /// bundle; the cheapest bundle is kept when an endpoint serves the file in several.
+    pub async fn file_services(
+        &self,
+        file_map: &FileAvailbilityMap,
+    ) -> HashMap<String, Vec<ServiceEndpoint>> {
+        let mut availability = HashMap::new();
+        for (file_hash, served) in targets(file_map).await {
+            availability.insert(file_hash, served.lock().await.clone());
+        }
+        // Query each pair of endpoint and bundle once across files
+        let pairs: HashSet<(String, String)> = availability
+            .values()
+            .flat_map(|served| {
+                served.iter().flat_map(|(url, bundles)| {
+                    bundles
+                        .iter()
+                        .map(move |bundle| (url.clone(), bundle.clone()))
+                })
+            })
+            .collect();
+        let resolved: HashMap<(String, String), ServiceEndpoint> = stream::iter(pairs)
+            .map(|(url, bundle)| async move {
+                let service = self.bundle_availability(&bundle, &url).await;
+                ((url, bundle), service)
+            })
+            .buffer_unordered(self.concurrency)
+            .filter_map(|(pair, service)| async move {
+                match service {
+                    Ok(service) => Some((pair, service)),
+                    Err(e) => {
+                        tracing::debug!(
+                            pair = tracing::field::debug(&pair),
+                            error = e.to_string(),
+                            "Failed to resolve service for bundle"
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
+            .await;
+
+        availability
+            .into_iter()
+            .map(|(file_hash, served)| {
+                let mut services = served
+                    .iter()
+                    .filter_map(|(url, bundles)| {
+                        bundles
+                            .iter()
+                            .filter_map(|bundle| resolved.get(&(url.clone(), bundle.clone())))
+                            .min_by(|a, b| cmp_price(a, b))
+                            .cloned()
+                    })
+                    .collect::<Vec<_>>();
+                services.sort_by(cmp_price);
+                (file_hash, services)
+            })
+            .collect()
+    }
+
+    /// Rank the bundles served at the endpoints that overlap a target bundle, by the share
+    /// of the target's bytes and then files they contain, and then by the cheapest price
+    pub async fn bundle_compatibilities(
+        &self,
+        bundle_hash: &str,
+        endpoint_checklist: &[String],
+    ) -> Result<Vec<BundleCompatibility>, Error> {
+        let target = read_bundle(&self.ipfs_client, bundle_hash).await?;
+        let target = &target;
+
+        // Endpoints serving each bundle
+        let statuses = stream::iter(endpoint_checklist)
+            .map(|url| async move { (url, self.served_bundles(url).await) })
+            .buffer_unordered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        let mut servers: HashMap<String, Vec<String>> = HashMap::new();
+        for (url, bundles) in statuses {
+            match bundles {
+                Ok(bundles) => {
+                    for bundle in bundles {
+                        servers.entry(bundle).or_default().push(url.clone());
+                    }
+                }
+                Err(e) => {
+                    tracing::debug!(url, error = e.to_string(), "Failed to get served bundles")
+                }
+            }
+        }
+
+        let mut compatibilities =
+            stream::iter(servers)
+                .map(|(bundle, urls)| async move {
+                    self.bundle_compatibility(target, bundle, &urls).await
+                })
+                .buffer_unordered(self.concurrency)
+                .filter_map(|compatibility| async move { compatibility })
+                .collect::<Vec<_>>()
+                .await;
+        compatibilities.sort_by(|a, b| {
+            b.byte_percentage
+                .partial_cmp(&a.byte_percentage)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(
+                    b.file_percentage
+                        .partial_cmp(&a.file_percentage)
+                        .unwrap_or(std::cmp::Ordering::Equal),
+                )
+                .then_with(|| cmp_price(&a.providers[0], &b.providers[0]))
+        });
+        Ok(compatibilities)
+    }
+
+    /// Compare a served bundle against the target bundle; `None` if they share no file or
+    /// no endpoint prices the served bundle
+    async fn bundle_compatibility(
+        &self,
+        target: &Bundle,
+        bundle: String,
+        urls: &[String],
+    ) -> Option<BundleCompatibility> {
+        let manifest = match self.bundle_manifest(&bundle).await {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                tracing::debug!(bundle, error = e.to_string(), "Failed to read bundle");
+                return None;
+            }
+        };
+        let matched = target
+            .file_manifests
+            .iter()
+            .filter(|f| {
+                manifest
+                    .files
+                    .iter()
+                    .any(|file| file.hash == f.meta_info.hash)
+            })
+            .collect::<Vec<_>>();
+        if matched.is_empty() {
+            return None;
+        }
+
+        let mut providers = stream::iter(urls)
+            .map(|url| self.bundle_availability(&bundle, url))
+            .buffer_unordered(self.concurrency)
+            .filter_map(|service| async move { service.ok() })
+            .collect::<Vec<_>>()
+            .await;
+        if providers.is_empty() {
+            return None;
+        }
+        providers.sort_by(cmp_price);
+
+        let matched_bytes: u64 = matched.iter().map(|f| f.file_manifest.total_bytes).sum();
+        let total_bytes: u64 = target
+            .file_manifests
+            .iter()
+            .map(|f| f.file_manifest.total_bytes)
+            .sum();
+        Some(BundleCompatibility {
+            bundle,
+            matched_files: matched.iter().map(|f| f.meta_info.clone()).collect(),
+            file_percentage: percentage(matched.len() as u64, target.file_manifests.len() as u64),
+            byte_percentage: percentage(matched_bytes, total_bytes),
+            providers,
+        })
+    }
+
     /// Gather file availability
     pub async fn file_availability(
         &self,
@@ -150,21 +430,16 @@ impl Finder {
         file_map: FileAvailbilityMap,
     ) -> Result<(), Error> {
         let indexer_endpoint = url.to_string();
-        let bundles = indexer_bundles(&self.http_client, url).await?;
+        let bundles = self.served_bundles(url).await?;
+        let targets = targets(&file_map).await;
 
         // Map of indexer_endpoints to served manifests
         // For each endpoint, populate indexer_map with the available files
         for bundle in bundles {
-            let file_hashes: Vec<String> = fetch_bundle_from_ipfs(&self.ipfs_client, &bundle)
-                .await?
-                .files
-                .iter()
-                .map(|file| file.hash.clone())
-                .collect();
-            let file_map_lock = file_map.lock().await;
-            for (target_file, availability_map) in file_map_lock.iter() {
+            let manifest = self.bundle_manifest(&bundle).await?;
+            for (target_file, availability_map) in &targets {
                 // Record serving indexer and bundle for each target file
-                if file_hashes.contains(target_file) {
+                if manifest.files.iter().any(|file| &file.hash == target_file) {
                     availability_map
                         .lock()
                         .await
@@ -222,29 +497,127 @@ impl Finder {
             }
         }
     }
+}
 
-    /// Should ping indexer cost endpoint for delicate cost model processing
-    pub fn fees() -> GRT {
-        GRT(UDecimal18::from(1))
+/// Merge endpoints discovered from the network with configured ones, configured first and
+/// without duplicates
+pub fn merge_endpoints(configured: &[String], discovered: &[String]) -> Vec<String> {
+    let mut endpoints: Vec<String> = vec![];
+    for url in configured.iter().chain(discovered) {
+        let url = url.trim().trim_end_matches('/');
+        if !url.is_empty() && !endpoints.iter().any(|e| e == url) {
+            endpoints.push(url.to_string());
+        }
     }
+    endpoints
+}
+
+/// Configured endpoints override discovered endpoints of the same operator, such as an
+/// indexer's internal URL taking the place of its registered public URL
+pub fn prefer_configured(
+    services: Vec<ServiceEndpoint>,
+    configured: &[String],
+) -> Vec<ServiceEndpoint> {
+    let is_configured = |service: &ServiceEndpoint| {
+        configured
+            .iter()
+            .any(|url| url.trim().trim_end_matches('/') == service.service_endpoint)
+    };
+    let configured_operators = services
+        .iter()
+        .filter(|service| is_configured(service))
+        .map(|service| service.operator.clone())
+        .collect::<HashSet<_>>();
+    services
+        .into_iter()
+        .filter(|service| {
+            is_configured(service) || !configured_operators.contains(&service.operator)
+        })
+        .collect()
 }
 
 /// Check if there is a key in target_hashes where the corresponding availability is empty
 pub async fn unavailable_files(file_map: &FileAvailbilityMap) -> Vec<String> {
     let mut missing_file = vec![];
-    let hashes = file_map.lock().await;
-    for (key, inner_map_arc) in hashes.iter() {
+    for (key, inner_map_arc) in targets(file_map).await {
         let inner_map = inner_map_arc.lock().await;
         if inner_map.is_empty() {
-            missing_file.push(key.clone());
+            missing_file.push(key);
         }
     }
     missing_file
 }
 
+/// Target files and their availability, taken out of the outer lock so that it is never held
+/// while waiting on the availability of a file
+async fn targets(
+    file_map: &FileAvailbilityMap,
+) -> Vec<(String, Arc<Mutex<HashMap<String, Vec<String>>>>)> {
+    file_map
+        .lock()
+        .await
+        .iter()
+        .map(|(hash, availability)| (hash.clone(), availability.clone()))
+        .collect()
+}
+
+fn percentage(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 100.0;
+    }
+    part as f64 / total as f64 * 100.0
+}
+
+fn cmp_price(a: &ServiceEndpoint, b: &ServiceEndpoint) -> std::cmp::Ordering {
+    a.price_per_byte
+        .partial_cmp(&b.price_per_byte)
+        .unwrap_or(std::cmp::Ordering::Equal)
+}
+
 //TODO: directly access the field instead
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Operator {
     #[serde(alias = "publicKey")]
     pub public_key: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(operator: &str, url: &str) -> ServiceEndpoint {
+        ServiceEndpoint {
+            operator: operator.to_string(),
+            service_endpoint: url.to_string(),
+            deployment: "QmBundle".to_string(),
+            price_per_byte: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_merge_endpoints() {
+        let configured = vec!["http://a.xyz/".to_string(), "http://b.xyz".to_string()];
+        let discovered = vec!["http://b.xyz".to_string(), "http://c.xyz".to_string()];
+        assert_eq!(
+            merge_endpoints(&configured, &discovered),
+            vec!["http://a.xyz", "http://b.xyz", "http://c.xyz"]
+        );
+    }
+
+    #[test]
+    fn test_configured_endpoint_overrides_operator() {
+        let services = vec![
+            service("0xa", "http://public-a.xyz"),
+            service("0xa", "http://internal-a:7600"),
+            service("0xb", "http://b.xyz"),
+        ];
+        let preferred = prefer_configured(services, &["http://internal-a:7600/".to_string()]);
+        assert_eq!(
+            preferred,
+            vec![
+                service("0xa", "http://internal-a:7600"),
+                service("0xb", "http://b.xyz"),
+            ]
+        );
+    }
+}
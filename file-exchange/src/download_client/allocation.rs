@@ -0,0 +1,162 @@
+use alloy_primitives::Address;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::{
+    discover::{cache::FetchCache, ServiceEndpoint},
+    errors::Error,
+    graphql::network_query::{active_allocations, Allocation},
+};
+
+/// Active allocations of providers on the bundles they serve, which receipts are issued
+/// against. Allocations are looked up in the network subgraph and cached per provider and
+/// deployment, then looked up again after the TTL or once a provider rejects a payment, so a
+/// closed allocation is replaced by the indexer's new one.
+#[derive(Debug)]
+pub struct Allocations {
+    http_client: reqwest::Client,
+    network_subgraph: String,
+    entries: FetchCache<Address>,
+}
+
+impl Allocations {
+    pub fn new(network_subgraph: &str, ttl: Duration) -> Self {
+        Allocations {
+            http_client: reqwest::Client::new(),
+            network_subgraph: network_subgraph.to_string(),
+            entries: FetchCache::new(Some(ttl)),
+        }
+    }
+
+    /// Active allocation of the provider on the deployment it serves
+    pub async fn allocation(&self, service: &ServiceEndpoint) -> Result<Address, Error> {
+        self.entries
+            .get_or_fetch(&cache_key(service), || async {
+                let allocations = active_allocations(
+                    &self.http_client,
+                    &self.network_subgraph,
+                    &service.deployment,
+                )
+                .await?;
+                let allocation = match_allocation(&allocations, service).ok_or_else(|| {
+                    Error::DataUnavailable(format!(
+                        "No active allocation of {} on {}",
+                        service.service_endpoint, service.deployment
+                    ))
+                })?;
+                tracing::debug!(
+                    service = service.service_endpoint,
+                    deployment = service.deployment,
+                    allocation = allocation.id,
+                    "Resolved provider allocation"
+                );
+                Address::from_str(&allocation.id)
+                    .map_err(|e| Error::InvalidConfig(format!("Invalid allocation ID: {}", e)))
+            })
+            .await
+    }
+
+    /// Look the allocation of the provider up again on the next receipt
+    pub fn invalidate(&self, service: &ServiceEndpoint) {
+        self.entries.invalidate(&cache_key(service));
+    }
+}
+
+fn cache_key(service: &ServiceEndpoint) -> String {
+    format!("{}|{}", service.service_endpoint, service.deployment)
+}
+
+/// Find the allocation of the indexer behind a provider, by its registered URL, or by the
+/// operator it reports being the indexer or one of the indexer's operators
+pub fn match_allocation<'a>(
+    allocations: &'a [Allocation],
+    service: &ServiceEndpoint,
+) -> Option<&'a Allocation> {
+    let normalize = |url: &str| url.trim().trim_end_matches('/').to_lowercase();
+    let endpoint = normalize(&service.service_endpoint);
+    let operator = service.operator.to_lowercase();
+    allocations
+        .iter()
+        .find(|a| a.indexer.url.as_deref().map(normalize) == Some(endpoint.clone()))
+        .or_else(|| {
+            allocations.iter().find(|a| {
+                a.indexer.id.to_lowercase() == operator
+                    || a.indexer.account.as_ref().is_some_and(|account| {
+                        account
+                            .operators
+                            .iter()
+                            .any(|o| o.id.to_lowercase() == operator)
+                    })
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    fn service(operator: &str, url: &str) -> ServiceEndpoint {
+        ServiceEndpoint {
+            operator: operator.to_string(),
+            service_endpoint: url.to_string(),
+            deployment: "QmBundle".to_string(),
+            price_per_byte: 1.0,
+        }
+    }
+
+    fn allocations_response() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "data": {
+                "allocations": [
+                    {
+                        "id": "0x1111111111111111111111111111111111111111",
+                        "indexer": {
+                            "id": "0xaaaa",
+                            "url": "https://indexer-a.xyz/",
+                            "account": { "operators": [] }
+                        }
+                    },
+                    {
+                        "id": "0x2222222222222222222222222222222222222222",
+                        "indexer": {
+                            "id": "0xbbbb",
+                            "url": null,
+                            "account": { "operators": [{ "id": "0xb0b0" }] }
+                        }
+                    }
+                ]
+            }
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_allocation_lookup_is_cached_per_provider() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(allocations_response())
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let allocations = Allocations::new(&server.uri(), Duration::from_secs(300));
+        // Matched by the registered URL, then by an operator of the indexer
+        let by_url = service("0xffff", "https://indexer-a.xyz");
+        let by_operator = service("0xB0B0", "http://10.0.0.2:7600");
+        assert_eq!(
+            allocations.allocation(&by_url).await.unwrap(),
+            Address::from_str("0x1111111111111111111111111111111111111111").unwrap()
+        );
+        assert_eq!(
+            allocations.allocation(&by_operator).await.unwrap(),
+            Address::from_str("0x2222222222222222222222222222222222222222").unwrap()
+        );
+        // Cached per provider, while unknown providers are looked up
+        allocations.allocation(&by_url).await.unwrap();
+        assert!(allocations
+            .allocation(&service("0xcccc", "http://unknown.xyz"))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::Error;
+
+/// Blocklist decision for a provider service endpoint
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BlockEntry {
+    pub reason: String,
+    /// Integrity failures and manual blocks are never re-admitted
+    pub permanent: bool,
+    /// Consecutive transient failures, doubling the cool-down each time
+    pub failures: u32,
+    /// Unix timestamp in milliseconds until which the provider cools down
+    pub until_ms: u64,
+}
+
+/// Providers excluded from chunk requests. Transient failures put a provider on an
+/// exponentially growing cool-down, after which it is probed with a single request at a time
+/// until a request succeeds; integrity failures, and transient failures that keep recurring,
+/// ban a provider for good.
+#[derive(Debug)]
+pub struct Blocklist {
+    entries: StdMutex<HashMap<String, BlockEntry>>,
+    base_cooldown: Duration,
+    max_cooldown: Duration,
+    /// Consecutive transient failures after which a provider is banned
+    max_failures: u32,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl Blocklist {
+    pub fn new(base_cooldown: Duration, max_cooldown: Duration, max_failures: u32) -> Self {
+        let base_cooldown = base_cooldown.max(Duration::from_secs(1));
+        Blocklist {
+            entries: StdMutex::new(HashMap::new()),
+            base_cooldown,
+            max_cooldown: max_cooldown.max(base_cooldown),
+            max_failures: max_failures.max(1),
+        }
+    }
+
+    /// Block a provider for the rest of the download
+    pub fn ban(&self, endpoint: &str, reason: String) {
+        tracing::warn!(endpoint, reason, "Ban provider");
+        self.entries.lock().unwrap().insert(
+            endpoint.to_string(),
+            BlockEntry {
+                reason,
+                permanent: true,
+                failures: 0,
+                until_ms: u64::MAX,
+            },
+        );
+    }
+
+    /// Cool a provider down after a transient failure, backing off exponentially, and ban it
+    /// once it failed too many times in a row. Failures while the provider is cooling down
+    /// are not counted.
+    pub fn cool_down(&self, endpoint: &str, reason: String) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .entry(endpoint.to_string())
+            .or_insert_with(|| BlockEntry {
+                reason: String::new(),
+                permanent: false,
+                failures: 0,
+                until_ms: 0,
+            });
+        // Requests in flight when the provider failed fail with it, so the failures of one
+        // outage count once
+        if entry.permanent || now_ms() < entry.until_ms {
+            return;
+        }
+        entry.failures += 1;
+        if entry.failures >= self.max_failures {
+            let reason = format!("{} consecutive failures, last: {}", entry.failures, reason);
+            tracing::warn!(endpoint, reason, "Ban provider");
+            entry.reason = reason;
+            entry.permanent = true;
+            entry.until_ms = u64::MAX;
+            return;
+        }
+        let cooldown = self
+            .base_cooldown
+            .saturating_mul(2u32.saturating_pow(entry.failures - 1))
+            .min(self.max_cooldown);
+        entry.until_ms = now_ms() + cooldown.as_millis() as u64;
+        entry.reason = reason;
+        tracing::info!(
+            endpoint,
+            failures = entry.failures,
+            cooldown = tracing::field::debug(&cooldown),
+            "Cool down provider"
+        );
+    }
+
+    /// A successful request re-admits a provider cooling down
+    pub fn record_success(&self, endpoint: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(endpoint).is_some_and(|e| !e.permanent) {
+            entries.remove(endpoint);
+        }
+    }
+
+    /// Whether no request may be made to the provider now
+    pub fn is_blocked(&self, endpoint: &str) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(endpoint)
+            .is_some_and(|e| e.permanent || now_ms() < e.until_ms)
+    }
+
+    /// Whether the provider finished cooling down and awaits a successful probe
+    pub fn is_probing(&self, endpoint: &str) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(endpoint)
+            .is_some_and(|e| !e.permanent && now_ms() >= e.until_ms)
+    }
+
+    /// Providers currently blocked
+    pub fn blocked(&self) -> HashSet<String> {
+        let now = now_ms();
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, e)| e.permanent || now < e.until_ms)
+            .map(|(endpoint, _)| endpoint.clone())
+            .collect()
+    }
+
+    /// Time until the next provider cooling down is re-admitted
+    pub fn next_readmission(&self) -> Option<Duration> {
+        let now = now_ms();
+        self.entries
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|e| !e.permanent)
+            .map(|e| Duration::from_millis(e.until_ms.saturating_sub(now)))
+            .min()
+    }
+
+    /// Wait until the next provider cooling down is re-admitted; false if every blocked
+    /// provider is banned, so waiting cannot make one available
+    pub async fn wait_readmission(&self) -> bool {
+        match self.next_readmission() {
+            Some(wait) => {
+                tracing::info!(
+                    wait = tracing::field::debug(&wait),
+                    "All providers cooling down, wait for re-admission"
+                );
+                tokio::time::sleep(wait + Duration::from_millis(10)).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn entries(&self) -> HashMap<String, BlockEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Store blocklist decisions and reasons as json
+    pub fn save(&self, file_path: &str) -> Result<(), Error> {
+        let serialized = serde_json::to_string(&self.entries()).map_err(Error::JsonError)?;
+        let mut file = File::create(file_path).map_err(Error::FileIOError)?;
+        file.write_all(serialized.as_bytes())
+            .map_err(Error::FileIOError)
+    }
+
+    /// Restore blocklist decisions stored by a previous attempt, if any
+    pub fn load(&self, file_path: &str) -> Result<(), Error> {
+        let Ok(mut file) = File::open(file_path) else {
+            return Ok(());
+        };
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .map_err(Error::FileIOError)?;
+        let entries: HashMap<String, BlockEntry> =
+            serde_json::from_str(&contents).map_err(Error::JsonError)?;
+        self.entries.lock().unwrap().extend(entries);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    /// End the cool-down of a provider as if its time had passed
+    fn expire(blocklist: &Blocklist, endpoint: &str) {
+        if let Some(entry) = blocklist.entries.lock().unwrap().get_mut(endpoint) {
+            entry.until_ms = entry.until_ms.min(now_ms());
+        }
+    }
+
+    #[test]
+    fn test_transient_failures_cool_down_and_readmit() {
+        let blocklist = Blocklist::new(Duration::from_secs(10), Duration::from_secs(25), 8);
+        blocklist.cool_down("a", "timeout".to_string());
+        assert!(blocklist.is_blocked("a"));
+        assert!(!blocklist.is_probing("a"));
+        assert!(blocklist.next_readmission().unwrap() <= Duration::from_secs(10));
+
+        // Back-off doubles and is capped
+        expire(&blocklist, "a");
+        blocklist.cool_down("a", "timeout".to_string());
+        assert!(blocklist.next_readmission().unwrap() > Duration::from_secs(10));
+        expire(&blocklist, "a");
+        blocklist.cool_down("a", "timeout".to_string());
+        assert!(blocklist.next_readmission().unwrap() <= Duration::from_secs(25));
+        assert_eq!(blocklist.entries()["a"].failures, 3);
+
+        blocklist.record_success("a");
+        assert!(!blocklist.is_blocked("a"));
+        assert!(blocklist.blocked().is_empty());
+    }
+
+    #[test]
+    fn test_expired_cool_down_is_probing() {
+        let blocklist = Blocklist::new(Duration::from_secs(1), Duration::from_secs(1), 8);
+        blocklist.entries.lock().unwrap().insert(
+            "a".to_string(),
+            BlockEntry {
+                reason: "timeout".to_string(),
+                permanent: false,
+                failures: 1,
+                until_ms: now_ms() - 1,
+            },
+        );
+        assert!(!blocklist.is_blocked("a"));
+        assert!(blocklist.is_probing("a"));
+    }
+
+    #[tokio::test]
+    async fn test_recurring_failures_give_up() {
+        let blocklist = Blocklist::new(Duration::from_secs(1), Duration::from_secs(1), 3);
+        // A provider failing every request, re-admitted as soon as its cool-down expires
+        let mut requests = 0;
+        loop {
+            if blocklist.is_blocked("a") {
+                if !blocklist.wait_readmission().await {
+                    break;
+                }
+                continue;
+            }
+            requests += 1;
+            blocklist.cool_down("a", "timeout".to_string());
+            expire(&blocklist, "a");
+        }
+
+        // The provider is banned after the last failure allowed, and no longer waited for
+        assert_eq!(requests, 3);
+        assert!(blocklist.entries()["a"].permanent);
+        assert_eq!(blocklist.next_readmission(), None);
+    }
+
+    #[test]
+    fn test_concurrent_failures_count_once() {
+        let blocklist = Arc::new(Blocklist::new(
+            Duration::from_secs(10),
+            Duration::from_secs(60),
+            2,
+        ));
+        // Every span in flight to a provider fails with the same outage
+        let spans: Vec<_> = (0..8)
+            .map(|_| {
+                let blocklist = blocklist.clone();
+                std::thread::spawn(move || blocklist.cool_down("a", "timeout".to_string()))
+            })
+            .collect();
+        for span in spans {
+            span.join().unwrap();
+        }
+
+        let entry = &blocklist.entries()["a"];
+        assert_eq!(entry.failures, 1);
+        assert!(!entry.permanent);
+        assert!(blocklist.next_readmission().unwrap() <= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_integrity_ban_is_permanent_and_persisted() {
+        let blocklist = Blocklist::new(Duration::from_secs(1), Duration::from_secs(1), 8);
+        blocklist.ban("a", "invalid chunk".to_string());
+        blocklist.record_success("a");
+        blocklist.cool_down("a", "timeout".to_string());
+        assert!(blocklist.is_blocked("a"));
+        assert_eq!(blocklist.next_readmission(), None);
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("blocklist.json");
+        let path = path.to_str().unwrap();
+        blocklist.save(path).unwrap();
+
+        let restored = Blocklist::new(Duration::from_secs(1), Duration::from_secs(1), 8);
+        restored.load(path).unwrap();
+        assert_eq!(restored.entries(), blocklist.entries());
+        assert!(restored.is_blocked("a"));
+    }
+}
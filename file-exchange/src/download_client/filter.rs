@@ -0,0 +1,107 @@
+use crate::manifest::FileManifestMeta;
+
+/// Selection of the files of a bundle to download by name. Patterns are exact file names or
+/// globs where `*` matches any sequence of characters and `?` matches a single character.
+#[derive(Debug, Clone, Default)]
+pub struct FileFilter {
+    /// Files must match one of these patterns; all files are included when empty
+    include: Vec<String>,
+    /// Files matching any of these patterns are left out
+    exclude: Vec<String>,
+}
+
+impl FileFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Self {
+        FileFilter {
+            include: include.to_vec(),
+            exclude: exclude.to_vec(),
+        }
+    }
+
+    /// Whether no file is filtered out
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| glob_match(p, name)))
+            && !self.exclude.iter().any(|p| glob_match(p, name))
+    }
+
+    /// File manifests with names passing the filter
+    pub fn select(&self, files: &[FileManifestMeta]) -> Vec<FileManifestMeta> {
+        files
+            .iter()
+            .filter(|f| self.matches(&f.meta_info.name))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Match a name against a glob pattern of `*` and `?` wildcards
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name position it was tried at, for backtracking
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the last `*` absorb one more character
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("users.sql", "users.sql"));
+        assert!(!glob_match("users.sql", "users.sql.gz"));
+        assert!(glob_match("*.sql", "users.sql"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("table_?.csv", "table_1.csv"));
+        assert!(!glob_match("table_?.csv", "table_10.csv"));
+        assert!(glob_match("a*b*c", "axxbyybzc"));
+        assert!(!glob_match("a*b*c", "axxbyyb"));
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let all = FileFilter::default();
+        assert!(all.is_empty());
+        assert!(all.matches("anything"));
+
+        let filter = FileFilter::new(
+            &["*.sql".to_string(), "schema.json".to_string()],
+            &["tmp_*".to_string()],
+        );
+        assert!(filter.matches("users.sql"));
+        assert!(filter.matches("schema.json"));
+        assert!(!filter.matches("tmp_users.sql"));
+        assert!(!filter.matches("README.md"));
+
+        let exclude_only = FileFilter::new(&[], &["*.log".to_string()]);
+        assert!(exclude_only.matches("users.sql"));
+        assert!(!exclude_only.matches("debug.log"));
+    }
+}
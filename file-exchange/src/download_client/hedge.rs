@@ -0,0 +1,150 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::{discover::ServiceEndpoint, download_client::range_request::DownloadRangeRequest};
+
+/// Number of recent chunk latencies the hedging deadline is taken from
+const LATENCY_WINDOW: usize = 512;
+/// Chunk latencies observed before any request is hedged
+const MIN_SAMPLES: usize = 16;
+
+/// Duplicate request for a chunk to a second provider, sent if the first request runs past
+/// the deadline
+#[derive(Debug)]
+pub struct Hedge {
+    pub service: ServiceEndpoint,
+    pub permits: Arc<Semaphore>,
+    pub request: DownloadRangeRequest,
+    pub deadline: Duration,
+}
+
+/// Hedged requests made during the download
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HedgeReport {
+    /// Hedged requests sent
+    pub issued: u64,
+    /// Hedged requests verified before the original request
+    pub won: u64,
+    /// Hedges skipped because the budget ran out
+    pub over_budget: u64,
+    /// Extra bytes requested, and paid for, by hedged requests
+    pub bytes: u64,
+}
+
+/// Deadline and byte budget of hedged requests
+#[derive(Debug)]
+pub struct Hedging {
+    percentile: Option<f64>,
+    latencies: StdMutex<VecDeque<Duration>>,
+    budget: AtomicU64,
+    report: StdMutex<HedgeReport>,
+}
+
+impl Hedging {
+    /// Hedging is disabled without a latency percentile
+    pub fn new(percentile: Option<f64>) -> Self {
+        Hedging {
+            percentile: percentile.map(|p| p.clamp(1.0, 100.0)),
+            latencies: StdMutex::new(VecDeque::with_capacity(LATENCY_WINDOW)),
+            budget: AtomicU64::new(0),
+            report: StdMutex::new(HedgeReport::default()),
+        }
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().unwrap();
+        if latencies.len() == LATENCY_WINDOW {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+
+    /// Time after which a chunk request is hedged, the configured percentile of recent
+    /// chunk latencies; `None` while hedging is disabled or latencies are too few to tell
+    pub fn deadline(&self) -> Option<Duration> {
+        let percentile = self.percentile?;
+        let mut latencies: Vec<Duration> = self.latencies.lock().unwrap().iter().copied().collect();
+        if latencies.len() < MIN_SAMPLES {
+            return None;
+        }
+        latencies.sort();
+        let rank = (percentile / 100.0 * latencies.len() as f64).ceil() as usize;
+        Some(latencies[rank.clamp(1, latencies.len()) - 1])
+    }
+
+    /// Allow hedged requests for another share of bytes
+    pub fn add_budget(&self, bytes: u64) {
+        self.budget.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Take bytes for a hedged request out of the budget, if it has enough left
+    pub fn try_spend(&self, bytes: u64) -> bool {
+        let spent = self
+            .budget
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(bytes)
+            })
+            .is_ok();
+        let mut report = self.report.lock().unwrap();
+        if spent {
+            report.issued += 1;
+            report.bytes += bytes;
+        } else {
+            report.over_budget += 1;
+        }
+        spent
+    }
+
+    pub fn record_won(&self) {
+        self.report.lock().unwrap().won += 1;
+    }
+
+    pub fn report(&self) -> HedgeReport {
+        self.report.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deadline_percentile() {
+        let hedging = Hedging::new(Some(90.0));
+        for ms in 1..MIN_SAMPLES as u64 {
+            hedging.record_latency(Duration::from_millis(ms * 10));
+        }
+        assert_eq!(hedging.deadline(), None);
+
+        for ms in MIN_SAMPLES as u64..=100 {
+            hedging.record_latency(Duration::from_millis(ms * 10));
+        }
+        assert_eq!(hedging.deadline(), Some(Duration::from_millis(900)));
+
+        let disabled = Hedging::new(None);
+        for _ in 0..MIN_SAMPLES {
+            disabled.record_latency(Duration::from_millis(10));
+        }
+        assert_eq!(disabled.deadline(), None);
+    }
+
+    #[test]
+    fn test_budget() {
+        let hedging = Hedging::new(Some(95.0));
+        hedging.add_budget(100);
+        assert!(hedging.try_spend(60));
+        assert!(!hedging.try_spend(60));
+        assert!(hedging.try_spend(40));
+        hedging.record_won();
+
+        let report = hedging.report();
+        assert_eq!(report.issued, 2);
+        assert_eq!(report.won, 1);
+        assert_eq!(report.over_budget, 1);
+        assert_eq!(report.bytes, 100);
+    }
+}
@@ -4,51 +4,92 @@ use ethers::providers::{Http, Middleware, Provider};
 
 use ethers_core::types::{H160, U256};
 use rand::seq::SliceRandom;
-use reqwest::header::{HeaderName, AUTHORIZATION};
+use reqwest::{
+    header::{HeaderName, AUTHORIZATION},
+    StatusCode,
+};
 use secp256k1::SecretKey;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
 
 use std::fs;
 use std::io::Read;
-use std::ops::Sub;
-use std::path::Path;
+use std::ops::{Range, Sub};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
 
-use tokio::sync::Mutex;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
-use crate::util::{read_json_to_map, store_map_as_json};
+use crate::util::{read_progress, store_progress, ProgressCache};
 use crate::{
     config::{DownloaderArgs, OnChainArgs, StorageMethod},
-    discover::{Finder, ServiceEndpoint},
+    discover::{merge_endpoints, prefer_configured, FileAvailbilityMap, Finder, ServiceEndpoint},
     download_client::range_request::download_chunk_and_write_to_file,
     errors::Error,
-    graphql::{allocation_id, escrow_query::escrow_balance},
+    graphql::escrow_query::escrow_balance,
     manifest::{
         ipfs::IpfsClient, manifest_fetcher::read_bundle, store::Store, Bundle, FileManifestMeta,
     },
+    throttle::Throttle,
     transaction_manager::TransactionManager,
-    util::build_wallet,
+    util::{build_wallet, GRT},
 };
 
-use self::{range_request::DownloadRangeRequest, signer::ReceiptSigner};
+use self::{
+    allocation::Allocations,
+    blocklist::Blocklist,
+    filter::FileFilter,
+    hedge::{Hedge, HedgeReport, Hedging},
+    partial::PartialFile,
+    range_request::{DownloadRangeRequest, SpanResult},
+    retry::RetryPolicy,
+    scoring::ProviderScores,
+    signer::ReceiptSigner,
+    spend::{SpendReport, Spending},
+};
 
+pub mod allocation;
+pub mod blocklist;
+pub mod filter;
+pub mod hedge;
+pub mod partial;
 pub mod range_request;
+pub mod retry;
+pub mod scoring;
 pub mod signer;
+pub mod spend;
 
 pub struct Downloader {
     config: DownloaderArgs,
     http_client: reqwest::Client,
     bundle: Bundle,
+    // Files of the bundle selected for download by name
+    file_filter: FileFilter,
     _gateway_url: Option<String>,
     indexer_urls: Arc<StdMutex<Vec<ServiceEndpoint>>>,
-    indexer_blocklist: Arc<StdMutex<HashSet<String>>>,
+    // Services of individual files, each in its own bundle, when no provider serves the whole
+    // bundle; keyed by file manifest hash
+    file_services: StdMutex<HashMap<String, Vec<ServiceEndpoint>>>,
+    indexer_blocklist: Arc<Blocklist>,
     // key is the file manifest identifier (IPFS hash) and value is a HashSet of downloaded chunk indices
     pub target_chunks: Arc<StdMutex<HashMap<String, HashSet<u64>>>>,
     bundle_finder: Finder,
-    payment: PaymentMethod,
+    payment: Arc<PaymentMethod>,
     store: Store,
+    // Bound chunk requests in flight, in total and per provider service endpoint
+    chunk_permits: Arc<Semaphore>,
+    provider_permits: StdMutex<HashMap<String, Arc<Semaphore>>>,
+    // Live statistics of providers weighting chunk assignment
+    provider_scores: Arc<ProviderScores>,
+    // Deadline and budget of duplicate requests for slow chunks
+    hedging: Arc<Hedging>,
+    // Bandwidth limits in total and per provider
+    throttle: Arc<Throttle>,
+    // Value of receipts issued against the spend budget
+    spending: Arc<Spending>,
 }
 
 /// A downloader can either provide a free query auth token or receipt signer
@@ -57,10 +98,221 @@ pub enum PaymentMethod {
     PaidQuery(OnChainSigner),
 }
 
+impl PaymentMethod {
+    /// Make a header for chunk request authorization either free or paid; receipts are issued
+    /// against the provider's active allocation on the bundle it serves, valued at the bytes
+    /// requested times the provider's price per byte
+    pub async fn header(
+        &self,
+        service: &ServiceEndpoint,
+        bytes: u64,
+    ) -> Result<(HeaderName, String), Error> {
+        match self {
+            PaymentMethod::FreeQuery(token) => Ok((AUTHORIZATION, token.to_string())),
+            PaymentMethod::PaidQuery(signer) => {
+                let allocation = signer.allocations.allocation(service).await?;
+                let fee = GRT::for_bytes(service.price_per_byte, bytes)?;
+                // Receipts are accounted for before they are issued, so concurrent requests
+                // cannot go over the budget together
+                signer.spending.try_spend(&service.service_endpoint, fee)?;
+                let receipt = match signer.receipt_signer.create_receipt(allocation, &fee).await {
+                    Ok(receipt) => receipt,
+                    Err(e) => {
+                        signer.spending.refund(&service.service_endpoint, fee);
+                        return Err(e);
+                    }
+                };
+                Ok((
+                    // HeaderName::from_str("Scalar-Receipt").unwrap(),
+                    HeaderName::from_str("scalar-receipt").unwrap(),
+                    receipt.serialize(),
+                ))
+            }
+        }
+    }
+
+    /// Whether receipts can be issued to the provider, which needs an active allocation
+    pub async fn accepts(&self, service: &ServiceEndpoint) -> bool {
+        match self {
+            PaymentMethod::FreeQuery(_) => true,
+            PaymentMethod::PaidQuery(signer) => {
+                match signer.allocations.allocation(service).await {
+                    Ok(_) => true,
+                    Err(e) => {
+                        tracing::warn!(
+                            service = service.service_endpoint,
+                            error = e.to_string(),
+                            "Skip provider without an active allocation"
+                        );
+                        false
+                    }
+                }
+            }
+        }
+    }
+
+    /// Look the provider's allocation up again for the next receipt, as a rejected payment
+    /// may be for an allocation that has since closed
+    pub fn refresh_allocation(&self, service: &ServiceEndpoint) {
+        if let PaymentMethod::PaidQuery(signer) = self {
+            signer.allocations.invalidate(service);
+        }
+    }
+}
+
+/// Shared state a chunk task reports the outcome of its requests to
+struct ChunkContext {
+    client: reqwest::Client,
+    payment: Arc<PaymentMethod>,
+    chunk_permits: Arc<Semaphore>,
+    blocklist: Arc<Blocklist>,
+    scores: Arc<ProviderScores>,
+    hedging: Arc<Hedging>,
+}
+
+impl ChunkContext {
+    /// Request a span of chunks from a provider. With a hedge planned, a duplicate request
+    /// goes to the hedge provider once the deadline passes; the first request to verify all
+    /// chunks wins and the other request is cancelled.
+    async fn download(
+        &self,
+        service: &ServiceEndpoint,
+        request: DownloadRangeRequest,
+        hedge: Option<Hedge>,
+    ) -> SpanResult {
+        let bytes = request.end - request.start + 1;
+        let chunks = request.chunk_hashes.len() as u32;
+        let started = Instant::now();
+        let primary = download_chunk_and_write_to_file(&self.client, request, &self.payment);
+        tokio::pin!(primary);
+
+        let Some(Hedge {
+            service: hedge_service,
+            permits,
+            request: hedge_request,
+            deadline,
+        }) = hedge
+        else {
+            let result = primary.await;
+            self.record(service, &result, bytes, chunks, started.elapsed());
+            return result;
+        };
+        tokio::select! {
+            result = &mut primary => {
+                self.record(service, &result, bytes, chunks, started.elapsed());
+                return result;
+            }
+            _ = tokio::time::sleep(deadline) => {}
+        }
+
+        // Hedges only use spare request slots and budget
+        let (Ok(chunk_permit), Ok(provider_permit)) = (
+            self.chunk_permits.clone().try_acquire_owned(),
+            permits.try_acquire_owned(),
+        ) else {
+            let result = primary.await;
+            self.record(service, &result, bytes, chunks, started.elapsed());
+            return result;
+        };
+        if !self.hedging.try_spend(bytes) {
+            let result = primary.await;
+            self.record(service, &result, bytes, chunks, started.elapsed());
+            return result;
+        }
+        tracing::debug!(
+            service = tracing::field::debug(&service.service_endpoint),
+            hedge = tracing::field::debug(&hedge_service.service_endpoint),
+            deadline = tracing::field::debug(&deadline),
+            "Hedge slow chunk request"
+        );
+        let hedge_started = Instant::now();
+        let hedged = async {
+            let _permits = (chunk_permit, provider_permit);
+            download_chunk_and_write_to_file(&self.client, hedge_request, &self.payment).await
+        };
+        tokio::pin!(hedged);
+
+        tokio::select! {
+            result = &mut primary => {
+                self.record(service, &result, bytes, chunks, started.elapsed());
+                if result.error.is_none() {
+                    return result;
+                }
+                let hedge_result = hedged.await;
+                self.record(&hedge_service, &hedge_result, bytes, chunks, hedge_started.elapsed());
+                if hedge_result.error.is_none() {
+                    self.hedging.record_won();
+                }
+                result.merge(hedge_result)
+            }
+            hedge_result = &mut hedged => {
+                self.record(&hedge_service, &hedge_result, bytes, chunks, hedge_started.elapsed());
+                if hedge_result.error.is_none() {
+                    self.hedging.record_won();
+                    return hedge_result;
+                }
+                let result = primary.await;
+                self.record(service, &result, bytes, chunks, started.elapsed());
+                hedge_result.merge(result)
+            }
+        }
+    }
+
+    /// Update provider statistics, latencies and the blocklist with the result of a request
+    fn record(
+        &self,
+        service: &ServiceEndpoint,
+        result: &SpanResult,
+        bytes: u64,
+        chunks: u32,
+        elapsed: Duration,
+    ) {
+        // Running out of budget is no fault of the provider
+        if let Some(Error::BudgetExceeded(_)) = &result.error {
+            return;
+        }
+        let url = &service.service_endpoint;
+        self.scores.update(url, |stats| match &result.error {
+            None => stats.record_success(bytes, elapsed),
+            Some(Error::ChunkInvalid(_)) => stats.record_invalid(),
+            Some(_) => stats.record_error(),
+        });
+        match &result.error {
+            None => {
+                self.blocklist.record_success(url);
+                // Hedging deadlines are kept per chunk, independent of span lengths
+                self.hedging.record_latency(elapsed / chunks.max(1));
+            }
+            Some(e) => {
+                tracing::warn!(
+                    err = e.to_string(),
+                    url,
+                    verified = result.verified.len(),
+                    "File manifest download incomplete"
+                );
+                if let Error::ResponseStatus(
+                    StatusCode::UNAUTHORIZED | StatusCode::PAYMENT_REQUIRED,
+                    _,
+                ) = e
+                {
+                    self.payment.refresh_allocation(service);
+                }
+                // Invalid data bans the provider, other failures cool it down
+                match e {
+                    Error::ChunkInvalid(_) => self.blocklist.ban(url, e.to_string()),
+                    _ => self.blocklist.cool_down(url, e.to_string()),
+                }
+            }
+        }
+    }
+}
+
 pub struct OnChainSigner {
     #[allow(dead_code)]
     transaction_manager: TransactionManager,
     receipt_signer: ReceiptSigner,
+    allocations: Allocations,
+    spending: Arc<Spending>,
 }
 
 impl Downloader {
@@ -69,6 +321,14 @@ impl Downloader {
             .await
             .expect("Read bundle");
 
+        let progress = match &args.progress_file {
+            Some(file_path) => read_progress(file_path).expect("Progress cache ill-formatted"),
+            None => ProgressCache::default(),
+        };
+        let budget = (args.max_spend > 0.0)
+            .then(|| GRT::from_decimal(args.max_spend).expect("Parse max spend"));
+        let spending = Arc::new(Spending::new(budget, progress.spend));
+
         let payment = if let Some(token) = &args.free_query_auth_token {
             PaymentMethod::FreeQuery(token.clone())
         } else if let Some(mnemonic) = &args.mnemonic {
@@ -111,6 +371,11 @@ impl Downloader {
             PaymentMethod::PaidQuery(OnChainSigner {
                 transaction_manager,
                 receipt_signer,
+                allocations: Allocations::new(
+                    &args.network_subgraph,
+                    Duration::from_secs(args.allocation_cache_ttl),
+                ),
+                spending: spending.clone(),
             })
         } else {
             panic!("No payment wallet nor free query token provided");
@@ -118,24 +383,44 @@ impl Downloader {
 
         let store = Store::new(&args.storage_method).expect("Create store");
 
-        let target_chunks = if let Some(file_path) = &args.progress_file {
-            let map = read_json_to_map(file_path).expect("Progress cache ill-formatted");
-            Arc::new(StdMutex::new(map))
-        } else {
-            Arc::new(StdMutex::new(HashMap::new()))
-        };
+        let blocklist = Blocklist::new(
+            Duration::from_secs(args.blocklist_cooldown),
+            Duration::from_secs(args.blocklist_max_cooldown),
+            args.blocklist_max_failures,
+        );
+        if let Some(file_path) = &args.progress_file {
+            blocklist
+                .load(&blocklist_path(file_path))
+                .expect("Blocklist cache ill-formatted");
+        }
+
+        let target_chunks = Arc::new(StdMutex::new(progress.chunks));
 
         Downloader {
             config: args.clone(),
             http_client: reqwest::Client::new(),
             bundle,
+            file_filter: FileFilter::new(&args.include, &args.exclude),
             _gateway_url: args.gateway_url,
             indexer_urls: Arc::new(StdMutex::new(Vec::new())),
-            indexer_blocklist: Arc::new(StdMutex::new(HashSet::new())),
+            file_services: StdMutex::new(HashMap::new()),
+            indexer_blocklist: Arc::new(blocklist),
             target_chunks,
-            bundle_finder: Finder::new(ipfs_client),
-            payment,
+            bundle_finder: Finder::new(ipfs_client).with_limits(
+                args.discovery_concurrency,
+                Duration::from_secs(args.status_cache_ttl),
+            ),
+            payment: Arc::new(payment),
             store,
+            chunk_permits: Arc::new(Semaphore::new(args.chunk_concurrency.max(1))),
+            provider_permits: StdMutex::new(HashMap::new()),
+            provider_scores: Arc::new(ProviderScores::default()),
+            hedging: Arc::new(Hedging::new(args.hedge_percentile)),
+            throttle: Arc::new(Throttle::new(
+                args.max_download_rate,
+                args.max_provider_download_rate,
+            )),
+            spending,
         }
     }
 
@@ -148,17 +433,62 @@ impl Downloader {
     }
 
     pub fn add_to_indexer_blocklist(&self, endpoint: String) {
-        let mut blocklist = self.indexer_blocklist.lock().expect("Failed to lock mutex");
-        blocklist.insert(endpoint);
+        self.indexer_blocklist
+            .ban(&endpoint, "Blocked by user".to_string());
+    }
+
+    /// Providers blocked or cooling down, shared with the progress cache handlers
+    pub fn blocklist(&self) -> Arc<Blocklist> {
+        self.indexer_blocklist.clone()
+    }
+
+    /// Change the bandwidth limits in bytes per second, in total and per provider, for
+    /// ongoing and new requests; 0 for no limit
+    pub fn set_bandwidth_limits(&self, total: u64, per_provider: u64) {
+        self.throttle.set_limits(total, per_provider);
+    }
+
+    /// Hedged requests made so far and the extra bytes they requested
+    pub fn hedge_report(&self) -> HedgeReport {
+        self.hedging.report()
     }
 
-    /// Read manifest to prepare chunks download
+    /// Value of receipts issued so far, by this run and in total
+    pub fn spend_report(&self) -> SpendReport {
+        self.spending.report()
+    }
+
+    /// Receipt values issued against the budget, shared with the progress cache handlers
+    pub fn spending(&self) -> Arc<Spending> {
+        self.spending.clone()
+    }
+
+    /// Files of the bundle selected by the include and exclude filters
+    pub fn selected_files(&self) -> Result<Vec<FileManifestMeta>, Error> {
+        let files = self.file_filter.select(&self.bundle.file_manifests);
+        if files.is_empty() {
+            return Err(Error::DataUnavailable(format!(
+                "No file in bundle {} matches the include and exclude filters",
+                self.config.ipfs_hash
+            )));
+        }
+        Ok(files)
+    }
+
+    /// Read manifest to prepare chunks download of the selected files
     pub fn init_target_chunks(&self, bundle: &Bundle) {
-        if let Some(file_path) = &self.config.progress_file {
+        let files = self.file_filter.select(&bundle.file_manifests);
+        {
             let mut target_chunks = self.target_chunks.lock().unwrap();
-            *target_chunks = read_json_to_map(file_path).expect("Progress cache ill-formatted");
+            if let Some(file_path) = &self.config.progress_file {
+                *target_chunks = read_progress(file_path)
+                    .expect("Progress cache ill-formatted")
+                    .chunks;
+            }
+            // Progress of files no longer selected is not tracked
+            target_chunks.retain(|hash, _| files.iter().any(|f| &f.meta_info.hash == hash));
         }
-        for file_manifest_meta in &bundle.file_manifests {
+        for file_manifest_meta in &files {
             let mut target_chunks = self.target_chunks.lock().unwrap();
             let chunks_set = target_chunks
                 .entry(file_manifest_meta.meta_info.hash.clone())
@@ -173,6 +503,15 @@ impl Downloader {
     /// Read bundle manifiest and download the individual file manifests
     //TODO: update once there is payment
     pub async fn download_bundle(&self) -> Result<(), Error> {
+        let files = self.selected_files()?;
+        if !self.file_filter.is_empty() {
+            tracing::info!(
+                files = tracing::field::debug(
+                    files.iter().map(|f| &f.meta_info.name).collect::<Vec<_>>()
+                ),
+                "Download selected files of the bundle"
+            );
+        }
         self.init_target_chunks(&self.bundle);
         tracing::trace!(
             chunks = tracing::field::debug(self.target_chunks.clone()),
@@ -180,25 +519,25 @@ impl Downloader {
         );
 
         // check bundle availability from gateway/indexer_endpoints
-        self.availbility_check().await?;
+        self.availbility_check(&files).await?;
         // check balance availability if payment is enabled
-        self.escrow_check().await?;
+        self.escrow_check(&files).await?;
 
-        // Loop through file manifests for downloading
+        // Download the selected files of the bundle through a shared chunk queue
         let mut incomplete_progresses = HashMap::new();
-        for file_manifest in &self.bundle.file_manifests {
-            if let Err(e) = self.download_file_manifest(file_manifest.clone()).await {
-                tracing::warn!(
-                    hash = &file_manifest.meta_info.hash,
-                    error = e.to_string(),
-                    "Failed to download file"
-                );
-                incomplete_progresses.insert(
-                    file_manifest.meta_info.hash.clone(),
-                    self.remaining_chunks(&file_manifest.meta_info.hash)
-                        .into_iter()
-                        .collect(),
-                );
+        if let Err(e) = self.download_file_manifests(files.clone()).await {
+            tracing::warn!(error = e.to_string(), "Failed to download files");
+            for file_manifest in &files {
+                let remaining = self.remaining_chunks(&file_manifest.meta_info.hash);
+                if !remaining.is_empty() {
+                    incomplete_progresses.insert(
+                        file_manifest.meta_info.hash.clone(),
+                        remaining.into_iter().collect(),
+                    );
+                }
+            }
+            if incomplete_progresses.is_empty() {
+                return Err(e);
             }
         }
 
@@ -208,17 +547,32 @@ impl Downloader {
                 tracing::field::debug(&incomplete_progresses),
             );
             tracing::warn!(msg);
-            // store progress into a json file: {hash: missing_chunk_indices}
+            tracing::info!(
+                spend = tracing::field::debug(self.spending.report()),
+                "Receipts issued"
+            );
+            // store progress into a json file: {chunks: {hash: missing_chunk_indices}, spend}
             if let Some(file_path) = &self.config.progress_file {
-                store_map_as_json(&incomplete_progresses, file_path)?;
+                let progress = ProgressCache {
+                    chunks: incomplete_progresses,
+                    spend: self.spending.totals(),
+                };
+                store_progress(&progress, file_path)?;
+                self.indexer_blocklist.save(&blocklist_path(file_path))?;
             };
             return Err(Error::DataUnavailable(msg));
         }
 
-        tracing::info!("File manifests download completed");
+        tracing::info!(
+            providers = tracing::field::debug(self.provider_scores.snapshot()),
+            hedging = tracing::field::debug(self.hedging.report()),
+            spend = tracing::field::debug(self.spending.report()),
+            "File manifests download completed"
+        );
 
         if let Some(file_path) = &self.config.progress_file {
             let _ = fs::remove_file(file_path);
+            let _ = fs::remove_file(blocklist_path(file_path));
         };
 
         Ok(())
@@ -237,212 +591,435 @@ impl Downloader {
     /// Download a file by reading its chunk manifest
     //TODO: update once there is payment
     pub async fn download_file_manifest(&self, meta: FileManifestMeta) -> Result<(), Error> {
-        tracing::debug!(
-            file_spec = tracing::field::debug(&meta),
-            "Download file manifest"
-        );
+        self.download_file_manifests(vec![meta]).await
+    }
 
-        // If storage method is the local file system, directly write the ranges
-        // If remote object storage, first write ranges to a tmp file to complete the object
-        // Open the output file
-        let file = match &self.store.storage_method {
-            StorageMethod::LocalFiles(directory) => {
-                let file = File::create(Path::new(
-                    &(directory.main_dir.clone() + "/" + &meta.meta_info.name),
-                ))
-                .unwrap_or_else(|_| {
-                    panic!(
-                        "Cannot create file for writing the output at directory {}",
-                        &directory.main_dir
-                    )
-                });
+    /// Download files by streaming the chunks of all files through one shared queue, bounded
+    /// by the global and per-provider limits. Each file is finalized as soon as all of its
+    /// chunks are verified, independently of the other files.
+    pub async fn download_file_manifests(&self, metas: Vec<FileManifestMeta>) -> Result<(), Error> {
+        let mut files = HashMap::new();
+        for meta in metas {
+            tracing::debug!(
+                file_spec = tracing::field::debug(&meta),
+                "Download file manifest"
+            );
+            let partial = self.open_partial(&meta).await?;
+            files.insert(meta.meta_info.hash.clone(), (meta, partial));
+        }
+        // Hedged requests may add a share of the bytes left to download
+        let remaining_bytes: u64 = files
+            .iter()
+            .map(|(hash, (meta, _))| {
+                self.remaining_chunks(hash).len() as u64 * meta.file_manifest.chunk_size
+            })
+            .sum();
+        self.hedging
+            .add_budget((remaining_bytes as f64 * self.config.hedge_budget / 100.0) as u64);
 
-                Arc::new(Mutex::new(file))
-            }
-            StorageMethod::ObjectStorage(store) => {
-                let file = File::create(Path::new(
-                    &("tmp/".to_owned() + &store.bucket + "/" + &meta.meta_info.name),
-                ))
-                .unwrap_or_else(|_| {
-                    panic!(
-                        "Cannot create file for writing the output at tmp/{}",
-                        &store.bucket.clone()
-                    )
-                });
-                tracing::debug!("Created tmp directory");
-                Arc::new(Mutex::new(file))
+        let mut finalizing = JoinSet::new();
+        let scheduled = self.schedule_chunks(&mut files, &mut finalizing).await;
+
+        // Files completed before a scheduling failure are still finalized
+        let mut failures = vec![];
+        while let Some(result) = finalizing.join_next().await {
+            match result.map_err(|e| Error::DataUnavailable(e.to_string()))? {
+                Ok(()) => {}
+                Err((hash, e)) => {
+                    tracing::warn!(hash, error = e.to_string(), "Failed to finalize file");
+                    failures.push(format!("{}: {}", hash, e));
+                }
             }
+        }
+        scheduled?;
+        if !failures.is_empty() {
+            return Err(Error::DataUnavailable(format!(
+                "Failed to finalize files: {:?}",
+                failures
+            )));
+        }
+        Ok(())
+    }
+
+    /// Open the partial output of a file, dropping chunks already complete from its targets
+    async fn open_partial(&self, meta: &FileManifestMeta) -> Result<PartialFile, Error> {
+        // If storage method is the local file system, directly write the ranges
+        // If remote object storage, first write ranges to a tmp file to complete the object
+        // Chunks land in a partial file that keeps the progress of previous attempts
+        let output_path = self.output_path(meta);
+        let (partial, completed) = if self.config.verify_existing {
+            let meta = meta.clone();
+            tokio::task::spawn_blocking(move || PartialFile::open_verified(&output_path, &meta))
+                .await
+                .map_err(|e| Error::DataUnavailable(e.to_string()))??
+        } else {
+            PartialFile::open(&output_path, &meta.meta_info.hash)?
         };
+        if let Some(chunks) = self
+            .target_chunks
+            .lock()
+            .unwrap()
+            .get_mut(&meta.meta_info.hash)
+        {
+            chunks.retain(|i| !completed.contains(i));
+        }
+        Ok(partial)
+    }
 
-        while !self.remaining_chunks(&meta.meta_info.hash).is_empty() {
-            // Wait for all chunk tasks to complete and collect the results
-            let mut handles = Vec::new();
-            for i in self.remaining_chunks(&meta.meta_info.hash) {
-                let file_manifest_hash = meta.meta_info.hash.clone();
-                let client = self.http_client.clone();
+    /// Request the remaining chunks of the files until all are downloaded, handing each
+    /// completed file over for finalization
+    async fn schedule_chunks(
+        &self,
+        files: &mut HashMap<String, (FileManifestMeta, PartialFile)>,
+        finalizing: &mut JoinSet<Result<(), (String, Error)>>,
+    ) -> Result<(), Error> {
+        self.finalize_completed(files, finalizing);
+        while !files.is_empty() {
+            let mut queue: VecDeque<(String, Range<u64>)> = files
+                .keys()
+                .flat_map(|hash| {
+                    chunk_spans(self.remaining_chunks(hash), self.config.chunks_per_request)
+                        .into_iter()
+                        .map(move |span| (hash.clone(), span))
+                })
+                .collect();
+            let mut tasks = JoinSet::new();
+            while let Some((hash, span)) = queue.pop_front() {
+                // Requests in flight finish, but no more are made once the budget is used up
+                if self.spending.exhausted() {
+                    break;
+                }
+                // Finished tasks are joined while waiting for a request slot, so completed files
+                // are finalized without waiting for the round to end
+                let chunk_permit = loop {
+                    tokio::select! {
+                        permit = self.chunk_permits.clone().acquire_owned() => {
+                            break permit.map_err(|e| Error::DataUnavailable(e.to_string()))?;
+                        }
+                        Some(_) = tasks.join_next() => self.finalize_completed(files, finalizing),
+                    }
+                };
+                let Some((meta, partial)) = files.get(&hash) else {
+                    continue;
+                };
+                // When every provider is cooling down, wait for the next one to be re-admitted;
+                // providers failing repeatedly are banned, so the download gives up eventually
+                let (service, provider_permits) = loop {
+                    match self.pick_provider(meta, span.start) {
+                        Ok(picked) => break picked,
+                        Err(e) => {
+                            if !self.indexer_blocklist.wait_readmission().await {
+                                return Err(e);
+                            }
+                        }
+                    }
+                };
+                let provider_permit = provider_permits
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| Error::DataUnavailable(e.to_string()))?;
+
+                let file_manifest_hash = hash.clone();
                 //TODO: can utilize operator address for on-chain checks
-                let request = self.download_range_request(&meta, i, file.clone())?;
-                let payment = self.payment_header(&request.receiver).await?;
-                let block_list = self.indexer_blocklist.clone();
+                let request =
+                    self.download_range_request(meta, span.clone(), &service, partial.file.clone());
+                let hedge = self.hedge(meta, span, &service, partial);
+                let context = self.chunk_context();
                 let target_chunks: Arc<StdMutex<HashMap<String, HashSet<u64>>>> =
                     self.target_chunks.clone();
-                let url = request.query_endpoint.clone();
-                // Spawn a new asynchronous task for each range request
-                let handle: tokio::task::JoinHandle<Result<Arc<Mutex<File>>, Error>> =
-                    tokio::spawn(async move {
-                        match download_chunk_and_write_to_file(&client, request, payment).await {
-                            Ok(r) => {
-                                // Update downloaded status
-                                target_chunks
-                                    .lock()
-                                    .unwrap()
-                                    .entry(file_manifest_hash)
-                                    .or_default()
-                                    .remove(&i);
-                                tracing::trace!(i, "Chunk downloaded");
-                                Ok(r)
-                            }
-                            Err(e) => {
-                                // If the download fails, add the URL to the indexer_blocklist
-                                let url = match extract_base_url(&url) {
-                                    Some(url) => url.to_string(),
-                                    None => url,
-                                };
-                                tracing::warn!(
-                                    err = e.to_string(),
-                                    url,
-                                    "File manifest download incomplete"
-                                );
-                                block_list
-                                    .lock()
-                                    .expect("Cannot access blocklist")
-                                    .insert(url);
-                                Err(e)
-                            }
-                        }
-                    });
+                let progress = partial.progress();
+                let file = partial.file.clone();
+                // Spawn an asynchronous task for the range request, holding its permits
+                tasks.spawn(async move {
+                    let _permits = (chunk_permit, provider_permit);
+                    let result = context.download(&service, request, hedge).await;
+                    // Verified chunks are kept even if the rest of the span failed
+                    let verified = result.verified.clone();
+                    let recorded = tokio::task::spawn_blocking(move || {
+                        PartialFile::record_chunks(&file, &progress, &verified)
+                    })
+                    .await
+                    .map_err(|e| Error::DataUnavailable(e.to_string()))
+                    .and_then(|recorded| recorded);
+                    if let Err(e) = recorded {
+                        tracing::warn!(error = e.to_string(), "Failed to record chunk progress");
+                    }
+                    // Invalid chunks stay in the targets, to be requested from other providers
+                    if !result.invalid.is_empty() {
+                        tracing::warn!(
+                            file_manifest = file_manifest_hash,
+                            chunks = tracing::field::debug(&result.invalid),
+                            "Chunks failed verification, request again"
+                        );
+                    }
+                    for i in result.verified {
+                        // Update downloaded status
+                        target_chunks
+                            .lock()
+                            .unwrap()
+                            .entry(file_manifest_hash.clone())
+                            .or_default()
+                            .remove(&i);
+                        tracing::trace!(i, "Chunk downloaded");
+                    }
+                    match result.error {
+                        Some(e) => Err(e),
+                        None => Ok(()),
+                    }
+                });
+            }
 
-                handles.push(handle);
+            // Wait for the remaining chunk tasks; failed chunks are queued again in the next round
+            while let Some(result) = tasks.join_next().await {
+                let _ = result.map_err(|e| Error::DataUnavailable(e.to_string()))?;
+                self.finalize_completed(files, finalizing);
+            }
+            if self.spending.exhausted() && !files.is_empty() {
+                let report = self.spending.report();
+                return Err(Error::BudgetExceeded(format!(
+                    "Stopped with {} GRT spent of the {} GRT budget; raise --max-spend to resume",
+                    report.total.0,
+                    report.budget.unwrap_or_default().0
+                )));
             }
+        }
+        Ok(())
+    }
 
-            for handle in handles {
-                let _ = handle
+    /// Hand files without remaining chunks over for finalization
+    fn finalize_completed(
+        &self,
+        files: &mut HashMap<String, (FileManifestMeta, PartialFile)>,
+        finalizing: &mut JoinSet<Result<(), (String, Error)>>,
+    ) {
+        let completed: Vec<String> = files
+            .keys()
+            .filter(|hash| self.remaining_chunks(hash).is_empty())
+            .cloned()
+            .collect();
+        for hash in completed {
+            let (meta, partial) = files.remove(&hash).unwrap();
+            tracing::info!(
+                file_info = tracing::field::debug(&meta.meta_info),
+                "File finished"
+            );
+            let store = self.store.clone();
+            finalizing.spawn(async move {
+                finalize_output(store, meta, partial)
                     .await
-                    .map_err(|e| Error::DataUnavailable(e.to_string()))?;
+                    .map_err(|e| (hash, e))
+            });
+        }
+    }
+
+    /// Local path of a downloaded file; files for remote object storage are staged in tmp
+    fn output_path(&self, meta: &FileManifestMeta) -> PathBuf {
+        match &self.store.storage_method {
+            StorageMethod::LocalFiles(directory) => {
+                Path::new(&directory.main_dir).join(&meta.meta_info.name)
             }
+            StorageMethod::ObjectStorage(store) => Path::new("tmp")
+                .join(&store.bucket)
+                .join(&meta.meta_info.name),
         }
+    }
 
-        tracing::info!(
-            chunks = tracing::field::debug(self.target_chunks.clone()),
-            file_info = tracing::field::debug(&meta.meta_info),
-            "File finished"
-        );
-        // If remote storage is configured, write to remote store and clean temp
-        if let StorageMethod::ObjectStorage(store) = &self.store.storage_method {
-            let file_path = &("tmp/".to_owned() + &store.bucket + "/" + &meta.meta_info.name);
-            let bytes = read_file_contents(file_path).await?;
-            let write_id = self
-                .store
-                .multipart_write(&meta.meta_info.name, &bytes, None)
-                .await;
-
-            tracing::debug!("Wrote with id {write_id:?}; delete tmp file");
-            fs::remove_file(file_path).map_err(Error::FileIOError)?;
-        };
-        Ok(())
+    /// Plan a duplicate request for a chunk to another provider, when hedging is enabled and
+    /// recent latencies set a deadline
+    fn hedge(
+        &self,
+        meta: &FileManifestMeta,
+        span: Range<u64>,
+        service: &ServiceEndpoint,
+        partial: &PartialFile,
+    ) -> Option<Hedge> {
+        let deadline = self.hedging.deadline()? * (span.end - span.start) as u32;
+        let (hedge_service, permits) =
+            self.choose_provider(&meta.meta_info.hash, Some(&service.service_endpoint))?;
+        let request = self.download_range_request(meta, span, &hedge_service, partial.file.clone());
+        Some(Hedge {
+            service: hedge_service,
+            permits,
+            request,
+            deadline,
+        })
     }
 
-    /// Make a header for chunk request authorization either free or paid
-    async fn payment_header(&self, receiver: &str) -> Result<(HeaderName, String), Error> {
-        match &self.payment {
-            PaymentMethod::FreeQuery(token) => Ok((AUTHORIZATION, token.to_string())),
-            PaymentMethod::PaidQuery(signer) => {
-                let receipt = signer
-                    .receipt_signer
-                    .create_receipt(allocation_id(receiver), &Finder::fees())
-                    .await?;
-                Ok((
-                    // HeaderName::from_str("Scalar-Receipt").unwrap(),
-                    HeaderName::from_str("scalar-receipt").unwrap(),
-                    receipt.serialize(),
-                ))
-            }
+    /// Shared state a chunk task reports its requests to
+    fn chunk_context(&self) -> ChunkContext {
+        ChunkContext {
+            client: self.http_client.clone(),
+            payment: self.payment.clone(),
+            chunk_permits: self.chunk_permits.clone(),
+            blocklist: self.indexer_blocklist.clone(),
+            scores: self.provider_scores.clone(),
+            hedging: self.hedging.clone(),
         }
     }
 
-    /// Generate a request to download a chunk
-    fn download_range_request(
+    /// Pick a provider that is not blocklisted. Providers with free request slots are chosen at
+    /// random in proportion to their weight under the selection policy; when every provider is
+    /// busy, the one with the highest weight is picked to wait on.
+    fn pick_provider(
         &self,
         meta: &FileManifestMeta,
         i: u64,
-        file: Arc<Mutex<File>>,
-    ) -> Result<DownloadRangeRequest, Error> {
+    ) -> Result<(ServiceEndpoint, Arc<Semaphore>), Error> {
+        match self.choose_provider(&meta.meta_info.hash, None) {
+            Some((service, permits)) => {
+                tracing::debug!(
+                    service = tracing::field::debug(&service),
+                    chunk = i,
+                    file_manifest = meta.meta_info.hash,
+                    "Picked provider"
+                );
+                Ok((service, permits))
+            }
+            None => {
+                let err_msg = "No operator serving the file, data unavailable".to_string();
+                tracing::warn!(err_msg);
+                Err(Error::DataUnavailable(err_msg.to_string()))
+            }
+        }
+    }
+
+    /// Choose a provider of a file by weight among those not blocklisted, other than an
+    /// excluded one
+    fn choose_provider(
+        &self,
+        file_hash: &str,
+        exclude: Option<&str>,
+    ) -> Option<(ServiceEndpoint, Arc<Semaphore>)> {
         let mut rng = rand::thread_rng();
-        let query_endpoints = &self.indexer_urls.lock().unwrap();
-        let blocklist = self
-            .indexer_blocklist
-            .lock()
-            .map_err(|e| Error::DataUnavailable(format!("Cannot unwrap indexer_blocklist: {}", e)))?
-            .clone();
+        let indexer_urls = self.indexer_urls.lock().unwrap();
+        let file_services = self.file_services.lock().unwrap();
+        // Files assembled across bundles are requested from the services of their own bundles
+        let query_endpoints = file_services.get(file_hash).unwrap_or(&indexer_urls);
+        let blocklist = self.indexer_blocklist.blocked();
         tracing::debug!(blocklist = tracing::field::debug(&blocklist), "blocklist");
         let filtered_endpoints = query_endpoints
             .iter()
             .filter(|url| !blocklist.contains(&url.service_endpoint))
+            .filter(|url| Some(url.service_endpoint.as_str()) != exclude)
             .cloned()
             .collect::<Vec<_>>();
-        let service = if let Some(service) = filtered_endpoints.choose(&mut rng).cloned() {
-            tracing::debug!(
-                service = tracing::field::debug(&service),
-                chunk = i,
-                file_manifest = meta.meta_info.hash,
-                "Randomly picked provider"
-            );
-            service
-        } else {
-            let err_msg = "No operator serving the file, data unavailable".to_string();
-            tracing::warn!(err_msg);
-            return Err(Error::DataUnavailable(err_msg.to_string()));
-        };
-        //TODO: do no add ipfs_hash here, construct query_endpoint after updating route 'files/id/:id'
-        let query_endpoint =
-            service.service_endpoint.clone() + "/files/id/" + &self.config.ipfs_hash;
+        let weights = self
+            .provider_scores
+            .weights(&self.config.selection_policy, &filtered_endpoints);
+
+        let mut provider_permits = self.provider_permits.lock().unwrap();
+        let limit = self.config.provider_chunk_concurrency.max(1);
+        let candidates = filtered_endpoints
+            .into_iter()
+            .zip(weights)
+            .map(|(service, weight)| {
+                let permits = provider_permits
+                    .entry(service.service_endpoint.clone())
+                    .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+                    .clone();
+                (service, permits, weight)
+            })
+            .collect::<Vec<_>>();
+        // Providers re-admitted after a cool-down are probed with one request at a time
+        let free = candidates
+            .iter()
+            .filter(|(service, permits, _)| {
+                if self.indexer_blocklist.is_probing(&service.service_endpoint) {
+                    permits.available_permits() == limit
+                } else {
+                    permits.available_permits() > 0
+                }
+            })
+            .collect::<Vec<_>>();
+        free.choose_weighted(&mut rng, |(_, _, weight)| *weight)
+            .ok()
+            .copied()
+            .or_else(|| {
+                candidates
+                    .iter()
+                    .max_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal))
+            })
+            .map(|(service, permits, _)| (service.clone(), permits.clone()))
+    }
+
+    /// Generate a request to download a contiguous span of chunks from a provider
+    fn download_range_request(
+        &self,
+        meta: &FileManifestMeta,
+        span: Range<u64>,
+        service: &ServiceEndpoint,
+        file: Arc<File>,
+    ) -> DownloadRangeRequest {
+        // Requests are made against the bundle the service serves the file in, which differs
+        // from the target bundle when files are assembled across bundles
+        let query_endpoint = service.service_endpoint.clone() + "/files/id/" + &service.deployment;
         let file_hash = meta.meta_info.hash.clone();
-        let start = i * meta.file_manifest.chunk_size;
-        let end = u64::min(
-            start + meta.file_manifest.chunk_size,
-            meta.file_manifest.total_bytes,
-        ) - 1;
-        let chunk_hash = meta.file_manifest.chunk_hashes[i as usize].clone();
-
-        Ok(DownloadRangeRequest {
-            receiver: service.operator.clone(),
+        let chunk_size = meta.file_manifest.chunk_size;
+        let start = span.start * chunk_size;
+        let end = u64::min(span.end * chunk_size, meta.file_manifest.total_bytes) - 1;
+        let chunk_hashes =
+            meta.file_manifest.chunk_hashes[span.start as usize..span.end as usize].to_vec();
+
+        DownloadRangeRequest {
+            service: service.clone(),
             query_endpoint,
             file_hash,
             start,
             end,
-            chunk_hash,
+            first_chunk: span.start,
+            chunk_size,
+            chunk_hashes,
             file,
-            max_retry: self.config.max_retry,
-        })
+            retry: RetryPolicy::from_config(&self.config),
+            throttle: self.throttle.clone(),
+        }
+    }
+
+    /// Indexer endpoints allocated to the bundle in the network subgraph, merged with the
+    /// configured endpoints
+    async fn endpoint_checklist(&self) -> Vec<String> {
+        if self.config.manual_endpoints_only || self.config.network_subgraph.is_empty() {
+            return merge_endpoints(&self.config.indexer_endpoints, &[]);
+        }
+        let discovered = match self
+            .bundle_finder
+            .network_endpoints(&self.config.network_subgraph, &self.config.ipfs_hash)
+            .await
+        {
+            Ok(urls) => urls,
+            Err(e) => {
+                tracing::warn!(
+                    error = e.to_string(),
+                    "Failed to discover indexer endpoints from the network subgraph, use configured endpoints"
+                );
+                vec![]
+            }
+        };
+        merge_endpoints(&self.config.indexer_endpoints, &discovered)
     }
 
     /// Make sure the requested bundle is available from at least 1 provider
-    async fn availbility_check(&self) -> Result<(), Error> {
-        let blocklist = self.indexer_blocklist.lock().unwrap().clone();
+    async fn availbility_check(&self, files: &[FileManifestMeta]) -> Result<(), Error> {
+        self.file_services.lock().unwrap().clear();
+        let blocklist = self.indexer_blocklist.blocked();
         let endpoints = &self
-            .config
-            .indexer_endpoints
-            .iter()
-            .filter(|url| !blocklist.contains(*url))
-            .cloned()
+            .endpoint_checklist()
+            .await
+            .into_iter()
+            .filter(|url| !blocklist.contains(url))
             .collect::<Vec<_>>();
-        let all_available = &self
+        let all_available = self
             .bundle_finder
             .bundle_availabilities(&self.config.ipfs_hash, endpoints)
             .await;
-        let mut sorted_endpoints = all_available.to_vec();
+        let mut sorted_endpoints = self
+            .payable(prefer_configured(
+                all_available,
+                &self.config.indexer_endpoints,
+            ))
+            .await;
         // Sort by price_per_byte in ascending order and select the top 'provider_concurrency' endpoints
-        //TODO: add other types of selection such as latency and reliability
+        // Chunks are then assigned among them by live latency, throughput and reliability
         sorted_endpoints.sort_by(|a, b| {
             a.price_per_byte
                 .partial_cmp(&b.price_per_byte)
@@ -461,19 +1038,17 @@ impl Downloader {
                 "No endpoint satisfy the bundle requested, sieve through available bundles for individual files"
             );
 
-            // check files availability from gateway/indexer_endpoints
+            // check availability of the selected files from gateway/indexer_endpoints
+            let file_hashes = files
+                .iter()
+                .map(|f| f.meta_info.hash.clone())
+                .collect::<Vec<_>>();
             match self
                 .bundle_finder
-                .file_discovery(&self.config.ipfs_hash, endpoints)
+                .files_discovery(&file_hashes, endpoints)
                 .await
             {
-                Ok(map) => {
-                    let msg = format!(
-                        "Files available on these available bundles: {:#?}",
-                        tracing::field::debug(&map.lock().await),
-                    );
-                    return Err(Error::DataUnavailable(msg));
-                }
+                Ok(map) => return self.assemble_across_bundles(&map).await,
                 Err(e) => {
                     let msg = format!(
                         "Cannot match the files: {:?}, {:?}",
@@ -488,20 +1063,72 @@ impl Downloader {
         Ok(())
     }
 
+    /// Request each file from the services of whichever bundles contain it, so the download
+    /// completes although no provider serves the whole bundle
+    async fn assemble_across_bundles(&self, map: &FileAvailbilityMap) -> Result<(), Error> {
+        let mut file_services = self.bundle_finder.file_services(map).await;
+        let missing = file_services
+            .iter()
+            .filter(|(_, services)| services.is_empty())
+            .map(|(hash, _)| hash.clone())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            let msg = format!(
+                "Cannot assemble the bundle, files unavailable: {:?}; files available on these bundles: {:#?}",
+                missing,
+                tracing::field::debug(&map.lock().await),
+            );
+            tracing::error!(msg);
+            return Err(Error::DataUnavailable(msg));
+        }
+
+        // Keep the cheapest 'provider_concurrency' services of each file, already sorted by price
+        let mut providers: Vec<ServiceEndpoint> = vec![];
+        for services in file_services.values_mut() {
+            *services = self.payable(std::mem::take(services)).await;
+            services.truncate(self.config.provider_concurrency as usize);
+            for service in services.iter() {
+                if !providers
+                    .iter()
+                    .any(|p| p.service_endpoint == service.service_endpoint)
+                {
+                    providers.push(service.clone());
+                }
+            }
+        }
+        tracing::info!(
+            file_services = tracing::field::debug(&file_services),
+            "Assemble the bundle from files served across bundles"
+        );
+        // All providers take part in escrow checks, each file is requested from its own
+        self.update_indexer_urls(&providers);
+        *self.file_services.lock().unwrap() = file_services;
+        Ok(())
+    }
+
+    /// Providers that can be paid with the payment method
+    async fn payable(&self, services: Vec<ServiceEndpoint>) -> Vec<ServiceEndpoint> {
+        let accepted =
+            futures::future::join_all(services.iter().map(|s| self.payment.accepts(s))).await;
+        services
+            .into_iter()
+            .zip(accepted)
+            .filter_map(|(service, accepted)| accepted.then_some(service))
+            .collect()
+    }
+
     /// Check escrow balances with cheapest N providers (N is the downloader client configured provider concurrency)
     /// Make suggestion to individual escrow accounts if balance is low
     /// Error out if gross buying power is insufficient, otherwise proceed with downloading
-    async fn escrow_check(&self) -> Result<(), Error> {
+    async fn escrow_check(&self, files: &[FileManifestMeta]) -> Result<(), Error> {
         // check balance availability if payment is enabled
         tracing::trace!("Escrow account checks");
-        if let PaymentMethod::PaidQuery(on_chain) = &self.payment {
+        if let PaymentMethod::PaidQuery(on_chain) = self.payment.as_ref() {
             let fail_tolerance = 1.2_f64;
 
             let mut total_buying_power_in_bytes: f64 = 0.0;
-            // estimate the cost to download the bundle from each provider
-            let total_bytes = self
-                .bundle
-                .file_manifests
+            // estimate the cost to download the selected files from each provider
+            let total_bytes = files
                 .iter()
                 .map(|f| f.file_manifest.total_bytes)
                 .sum::<u64>();
@@ -621,20 +1248,70 @@ impl Downloader {
     }
 }
 
-/// extract base indexer_url from `indexer_url/bundles/id/bundle_id`
-fn extract_base_url(query_endpoint: &str) -> Option<&str> {
-    if let Some(index) = query_endpoint.find("/files/id/") {
-        Some(&query_endpoint[..index])
-    } else {
-        None
+/// Move a verified partial file into place, and upload it if remote storage is configured
+async fn finalize_output(
+    store: Store,
+    meta: FileManifestMeta,
+    partial: PartialFile,
+) -> Result<(), Error> {
+    // Every chunk is verified, move the output into place
+    let output_path = partial.finalize(meta.file_manifest.total_bytes).await?;
+    // If remote storage is configured, write to remote store and clean temp
+    if let StorageMethod::ObjectStorage(_) = &store.storage_method {
+        let bytes = read_file_contents(&output_path).await?;
+        let write_id = store
+            .multipart_write(&meta.meta_info.name, bytes.into(), None)
+            .await?;
+
+        tracing::debug!("Wrote with id {write_id:?}; delete tmp file");
+        fs::remove_file(&output_path).map_err(Error::FileIOError)?;
+    };
+    Ok(())
+}
+
+/// Group chunk indices into contiguous spans of at most `max_chunks` chunks
+fn chunk_spans(mut chunks: Vec<u64>, max_chunks: usize) -> Vec<Range<u64>> {
+    chunks.sort_unstable();
+    let max_chunks = max_chunks.max(1) as u64;
+    let mut spans: Vec<Range<u64>> = vec![];
+    for i in chunks {
+        match spans.last_mut() {
+            Some(span) if span.end == i && span.end - span.start < max_chunks => span.end += 1,
+            _ => spans.push(i..i + 1),
+        }
     }
+    spans
+}
+
+/// Blocklist decisions are stored alongside the progress cache
+pub fn blocklist_path(progress_file: &str) -> String {
+    format!("{}.blocklist", progress_file)
 }
 
-async fn read_file_contents(file: &str) -> Result<Vec<u8>, Error> {
-    let mut file = File::open(Path::new(file))
-        .unwrap_or_else(|_| panic!("Cannot open file {} to transfer to object store", file));
+async fn read_file_contents(file: &Path) -> Result<Vec<u8>, Error> {
+    let mut file = File::open(file).unwrap_or_else(|_| {
+        panic!(
+            "Cannot open file {} to transfer to object store",
+            file.display()
+        )
+    });
     let mut contents = Vec::new();
     file.read_to_end(&mut contents)
         .map_err(Error::FileIOError)?;
     Ok(contents)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_spans() {
+        assert_eq!(
+            chunk_spans(vec![7, 0, 1, 2, 3, 4, 9, 10], 3),
+            vec![0..3, 3..5, 7..8, 9..11]
+        );
+        assert_eq!(chunk_spans(vec![2, 1], 0), vec![1..2, 2..3]);
+        assert!(chunk_spans(vec![], 4).is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+
+use crate::{
+    errors::Error,
+    manifest::{file_hasher::hash_chunk, FileManifestMeta},
+};
+
+/// An output file under download. Chunks are written to `<name>.partial` and the index of
+/// each verified chunk is appended to the sidecar `<name>.partial.progress`, whose first line
+/// is the file manifest hash. The partial file only takes the output name once finalized.
+#[derive(Debug)]
+pub struct PartialFile {
+    path: PathBuf,
+    pub file: Arc<File>,
+    progress: Arc<StdMutex<File>>,
+}
+
+impl PartialFile {
+    pub fn partial_path(path: &Path) -> PathBuf {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        PathBuf::from(partial)
+    }
+
+    pub fn progress_path(path: &Path) -> PathBuf {
+        let mut progress = path.as_os_str().to_owned();
+        progress.push(".partial.progress");
+        PathBuf::from(progress)
+    }
+
+    /// Open the partial file of an output without truncating it, and return the chunks
+    /// recorded as complete by a previous attempt at the same file manifest
+    pub fn open(path: &Path, file_hash: &str) -> Result<(Self, HashSet<u64>), Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(Error::FileIOError)?;
+        }
+        let partial_path = Self::partial_path(path);
+        let progress_path = Self::progress_path(path);
+
+        let mut completed = HashSet::new();
+        let mut resumable = false;
+        if let Ok(progress) = File::open(&progress_path) {
+            let mut lines = BufReader::new(progress).lines();
+            if let Some(Ok(hash)) = lines.next() {
+                resumable = hash == file_hash;
+            }
+            if resumable {
+                // A torn last line from a crash is skipped, its chunk is downloaded again
+                completed.extend(lines.map_while(Result::ok).filter_map(|l| l.parse().ok()));
+            }
+        }
+
+        // Discard data of an unrelated file left under the same name
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(!resumable)
+            .open(&partial_path)
+            .map_err(Error::FileIOError)?;
+        let progress = if resumable {
+            OpenOptions::new()
+                .append(true)
+                .open(&progress_path)
+                .map_err(Error::FileIOError)?
+        } else {
+            let mut progress = File::create(&progress_path).map_err(Error::FileIOError)?;
+            writeln!(progress, "{}", file_hash).map_err(Error::FileIOError)?;
+            progress
+        };
+
+        tracing::debug!(
+            partial = tracing::field::debug(&partial_path),
+            completed = completed.len(),
+            "Opened partial file"
+        );
+        Ok((
+            PartialFile {
+                path: path.to_path_buf(),
+                file: Arc::new(file),
+                progress: Arc::new(StdMutex::new(progress)),
+            },
+            completed,
+        ))
+    }
+
+    /// Open the partial file of an output and hash its existing data against the chunk hashes
+    /// instead of trusting the progress record. An output already under its final name, such
+    /// as one copied from another machine, is taken as the partial file.
+    pub fn open_verified(
+        path: &Path,
+        meta: &FileManifestMeta,
+    ) -> Result<(Self, HashSet<u64>), Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(Error::FileIOError)?;
+        }
+        let partial_path = Self::partial_path(path);
+        if !partial_path.exists() && path.exists() {
+            fs::rename(path, &partial_path).map_err(Error::FileIOError)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&partial_path)
+            .map_err(Error::FileIOError)?;
+        let existing_bytes = file.metadata().map_err(Error::FileIOError)?.len();
+
+        let manifest = &meta.file_manifest;
+        let mut completed = HashSet::new();
+        let mut buf = Vec::new();
+        for i in 0..(manifest.total_bytes / manifest.chunk_size + 1) {
+            let start = i * manifest.chunk_size;
+            let end = u64::min(start + manifest.chunk_size, manifest.total_bytes);
+            if end > existing_bytes {
+                break;
+            }
+            buf.resize((end.saturating_sub(start)) as usize, 0);
+            file.seek(SeekFrom::Start(start))
+                .map_err(Error::FileIOError)?;
+            file.read_exact(&mut buf).map_err(Error::FileIOError)?;
+            if hash_chunk(&buf) == manifest.chunk_hashes[i as usize] {
+                completed.insert(i);
+            }
+        }
+
+        // Rewrite the progress record from the verified chunks
+        let mut progress = File::create(Self::progress_path(path)).map_err(Error::FileIOError)?;
+        writeln!(progress, "{}", meta.meta_info.hash).map_err(Error::FileIOError)?;
+        for i in &completed {
+            writeln!(progress, "{}", i).map_err(Error::FileIOError)?;
+        }
+
+        tracing::debug!(
+            partial = tracing::field::debug(&partial_path),
+            verified = completed.len(),
+            "Verified existing partial data"
+        );
+        Ok((
+            PartialFile {
+                path: path.to_path_buf(),
+                file: Arc::new(file),
+                progress: Arc::new(StdMutex::new(progress)),
+            },
+            completed,
+        ))
+    }
+
+    /// Handle to append verified chunk indices to the sidecar progress record
+    pub fn progress(&self) -> Arc<StdMutex<File>> {
+        self.progress.clone()
+    }
+
+    /// Record chunks as written and verified. The partial file is synced first, so a crash
+    /// cannot leave a chunk recorded whose data never reached the disk.
+    pub fn record_chunks(
+        file: &File,
+        progress: &StdMutex<File>,
+        indices: &[u64],
+    ) -> Result<(), Error> {
+        if indices.is_empty() {
+            return Ok(());
+        }
+        file.sync_data().map_err(Error::FileIOError)?;
+        let mut progress = progress.lock().unwrap();
+        for index in indices {
+            writeln!(progress, "{}", index).map_err(Error::FileIOError)?;
+        }
+        Ok(())
+    }
+
+    /// Cut the partial file to the size of the file, flush it to disk and move it to the output
+    /// name, removing the progress record. Data past the end, such as from an oversized output
+    /// taken as the partial file, is dropped; the directory is synced so the move survives a
+    /// crash.
+    pub async fn finalize(self, total_bytes: u64) -> Result<PathBuf, Error> {
+        let file = self.file.clone();
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+            file.set_len(total_bytes)?;
+            file.sync_all()?;
+            fs::rename(Self::partial_path(&path), &path)?;
+            #[cfg(unix)]
+            {
+                let parent = match path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+                File::open(parent)?.sync_all()?;
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|e| Error::DataUnavailable(e.to_string()))?
+        .map_err(Error::FileIOError)?;
+        let _ = fs::remove_file(Self::progress_path(&self.path));
+        Ok(self.path)
+    }
+}
+
+/// Write all data at an offset of a file shared across tasks, without moving a shared cursor
+pub fn write_all_at(file: &File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+    }
+    #[cfg(windows)]
+    {
+        let mut written = 0;
+        while written < data.len() {
+            let n = std::os::windows::fs::FileExt::seek_write(
+                file,
+                &data[written..],
+                offset + written as u64,
+            )?;
+            if n == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+            written += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{FileManifest, FileMetaInfo};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_partial_file_resume_and_finalize() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+
+        let (partial, completed) = PartialFile::open(&path, "QmHash").unwrap();
+        assert!(completed.is_empty());
+        write_all_at(&partial.file, b"5678", 4).unwrap();
+        PartialFile::record_chunks(&partial.file, &partial.progress(), &[1]).unwrap();
+        drop(partial);
+        assert!(!path.exists());
+
+        // Reopening keeps written data and recorded chunks
+        let (partial, completed) = PartialFile::open(&path, "QmHash").unwrap();
+        assert_eq!(completed, HashSet::from([1]));
+        write_all_at(&partial.file, b"1234", 0).unwrap();
+        PartialFile::record_chunks(&partial.file, &partial.progress(), &[0]).unwrap();
+        partial.finalize(8).await.unwrap();
+
+        let mut contents = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "12345678");
+        assert!(!PartialFile::partial_path(&path).exists());
+        assert!(!PartialFile::progress_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn test_partial_file_restarts_for_different_manifest() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+
+        let (partial, _) = PartialFile::open(&path, "QmHash").unwrap();
+        write_all_at(&partial.file, b"1234", 0).unwrap();
+        PartialFile::record_chunks(&partial.file, &partial.progress(), &[0]).unwrap();
+        drop(partial);
+
+        let (partial, completed) = PartialFile::open(&path, "QmOther").unwrap();
+        assert!(completed.is_empty());
+        assert_eq!(partial.file.metadata().unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_open_verified_keeps_only_matching_chunks() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let meta = FileManifestMeta {
+            meta_info: FileMetaInfo {
+                name: "data.bin".to_string(),
+                hash: "QmHash".to_string(),
+            },
+            file_manifest: FileManifest {
+                total_bytes: 10,
+                chunk_size: 4,
+                chunk_hashes: vec![hash_chunk(b"0123"), hash_chunk(b"4567"), hash_chunk(b"89")],
+            },
+        };
+
+        // A crash left a corrupted second chunk and a progress record claiming otherwise
+        fs::write(PartialFile::partial_path(&path), b"0123xxxx89").unwrap();
+        fs::write(PartialFile::progress_path(&path), "QmHash\n0\n1\n").unwrap();
+        let (_, recorded) = PartialFile::open(&path, "QmHash").unwrap();
+        assert_eq!(recorded, HashSet::from([0, 1]));
+
+        let (partial, completed) = PartialFile::open_verified(&path, &meta).unwrap();
+        assert_eq!(completed, HashSet::from([0, 2]));
+        assert!(!path.exists());
+        drop(partial);
+
+        // The progress record is corrected to the verified chunks
+        let (_, recorded) = PartialFile::open(&path, "QmHash").unwrap();
+        assert_eq!(recorded, HashSet::from([0, 2]));
+    }
+
+    #[tokio::test]
+    async fn test_open_verified_takes_output_as_partial() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let meta = FileManifestMeta {
+            meta_info: FileMetaInfo {
+                name: "data.bin".to_string(),
+                hash: "QmHash".to_string(),
+            },
+            file_manifest: FileManifest {
+                total_bytes: 6,
+                chunk_size: 4,
+                chunk_hashes: vec![hash_chunk(b"0123"), hash_chunk(b"45")],
+            },
+        };
+
+        // A file copied from another machine under the output name, without a progress record
+        fs::write(&path, b"xxxx45").unwrap();
+        let (_, completed) = PartialFile::open_verified(&path, &meta).unwrap();
+        assert_eq!(completed, HashSet::from([1]));
+        assert!(!path.exists());
+        assert!(PartialFile::partial_path(&path).exists());
+        fs::remove_file(PartialFile::partial_path(&path)).unwrap();
+
+        // An oversized output keeps its verified chunks, and loses the trailing data once
+        // finalized
+        fs::write(&path, b"xxxx45trailing").unwrap();
+        let (partial, completed) = PartialFile::open_verified(&path, &meta).unwrap();
+        assert_eq!(completed, HashSet::from([1]));
+        write_all_at(&partial.file, b"0123", 0).unwrap();
+        partial.finalize(6).await.unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"012345");
+    }
+}
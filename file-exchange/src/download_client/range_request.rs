@@ -1,103 +1,239 @@
-use bytes::Bytes;
+use bytes::BytesMut;
 
-use reqwest::{header::HeaderName, Client};
+use reqwest::{Client, Response};
 
 use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
 
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
 
-use crate::{errors::Error, manifest::file_hasher::verify_chunk};
+use crate::{
+    discover::ServiceEndpoint,
+    download_client::{
+        partial::write_all_at,
+        retry::{classify, retry_after, RetryDecision, RetryPolicy},
+        PaymentMethod,
+    },
+    errors::Error,
+    manifest::file_hasher::verify_chunk,
+    throttle::Throttle,
+};
 
+/// Range request for a contiguous run of chunks of a file
 #[derive(Debug, Clone)]
 pub struct DownloadRangeRequest {
-    pub receiver: String,
+    pub service: ServiceEndpoint,
     pub query_endpoint: String,
     pub file_hash: String,
     pub start: u64,
     pub end: u64,
-    pub chunk_hash: String,
-    pub file: Arc<Mutex<File>>,
-    pub max_retry: u64,
+    pub first_chunk: u64,
+    pub chunk_size: u64,
+    pub chunk_hashes: Vec<String>,
+    pub file: Arc<File>,
+    pub retry: RetryPolicy,
+    pub throttle: Arc<Throttle>,
 }
 
-/// Make request to download a chunk and write it to the file in position
+/// Chunks of a range request verified and written in position, and chunks received that
+/// failed verification. A transport error ends the request before the remaining chunks are
+/// received; invalid chunks are skipped and the rest of the span is still read.
+#[derive(Debug, Default)]
+pub struct SpanResult {
+    pub verified: Vec<u64>,
+    pub invalid: Vec<u64>,
+    pub error: Option<Error>,
+}
+
+impl SpanResult {
+    /// Merge the results of two requests for the same chunks
+    pub fn merge(self, other: SpanResult) -> SpanResult {
+        let mut verified = self.verified;
+        verified.extend(other.verified);
+        verified.sort_unstable();
+        verified.dedup();
+        let mut invalid = self.invalid;
+        invalid.extend(other.invalid);
+        invalid.retain(|i| verified.binary_search(i).is_err());
+        invalid.sort_unstable();
+        invalid.dedup();
+        let error = match (self.error, other.error) {
+            (Some(_), Some(e)) => Some(e),
+            _ => None,
+        };
+        SpanResult {
+            verified,
+            invalid,
+            error,
+        }
+    }
+
+    /// Number of chunks received from the start of the span, whether valid or not
+    fn received(&self) -> u64 {
+        (self.verified.len() + self.invalid.len()) as u64
+    }
+}
+
+impl DownloadRangeRequest {
+    /// Chunk indices covered by the request
+    pub fn chunks(&self) -> std::ops::Range<u64> {
+        self.first_chunk..self.first_chunk + self.chunk_hashes.len() as u64
+    }
+}
+
+/// Make a range request for a run of chunks and write each chunk in position as soon as it
+/// arrives and is verified. A transport failure is retried for the chunks not yet received;
+/// chunks failing verification are skipped, and the request ends with an error once the span
+/// is read. Each attempt is authorized on its own, so a receipt pays for exactly the bytes
+/// that attempt requests.
 pub async fn download_chunk_and_write_to_file(
     http_client: &Client,
     request: DownloadRangeRequest,
-    auth_header: (HeaderName, String),
-) -> Result<Arc<Mutex<File>>, Error> {
+    payment: &PaymentMethod,
+) -> SpanResult {
     let mut attempts = 0;
+    let mut backoff = request.retry.backoff();
+    let mut result = SpanResult::default();
 
     tracing::debug!(
         request = tracing::field::debug(&request),
         "Making a range request"
     );
     loop {
-        // Make the range request to download the chunk
-        match request_chunk(
+        // Request the chunks not yet received
+        let next = request.first_chunk + result.received();
+        let remaining = request.chunk_hashes.len() as u32 - result.received() as u32;
+        let error = match request_chunk(
             http_client,
-            &request.query_endpoint,
-            auth_header.clone(),
-            &request.file_hash,
-            request.start,
-            request.end,
+            &request,
+            payment,
+            next * request.chunk_size,
+            request.retry.timeout * remaining,
         )
         .await
         {
-            Ok(data) => {
-                if verify_chunk(&data, &request.chunk_hash) {
-                    // Lock the file for writing
-                    let mut file_lock = request.file.lock().await;
-                    file_lock
-                        .seek(SeekFrom::Start(request.start))
-                        .map_err(Error::FileIOError)?;
-                    file_lock.write_all(&data).map_err(Error::FileIOError)?;
-                    drop(file_lock);
-                    return Ok(request.file); // Successfully written the chunk, exit loop
-                } else {
-                    // Immediately return and blacklist the indexer when a chunk received is invalid
-                    let msg = format!(
-                        "Failed to validate received chunk: {}",
-                        &request.query_endpoint
-                    );
-                    tracing::warn!(msg);
-                    return Err(Error::ChunkInvalid(msg));
-                }
-            }
-            Err(e) => tracing::error!("Chunk download error: {:?}", e),
+            Ok(response) => receive_chunks(response, &request, &mut result).await.err(),
+            Err(e) => Some(e),
+        };
+
+        // A provider that sent invalid chunks is not asked for the rest of the span again
+        if !result.invalid.is_empty() {
+            let msg = format!(
+                "Failed to validate received chunks {:?}: {}",
+                result.invalid, &request.query_endpoint
+            );
+            tracing::warn!(msg);
+            result.error = Some(Error::ChunkInvalid(msg));
+            return result;
         }
+        let Some(error) = error else {
+            return result;
+        };
 
         attempts += 1;
-        if attempts >= request.max_retry {
-            return Err(Error::DataUnavailable(
-                "Max retry attempts reached for chunk download".to_string(),
-            ));
-        }
+        let delay = match classify(&error) {
+            RetryDecision::Never => None,
+            _ if attempts >= request.retry.max_retry => None,
+            RetryDecision::Backoff => Some(backoff.next().unwrap_or(request.retry.max_delay)),
+            // A provider asking for a longer wait than allowed is left for others
+            RetryDecision::After(delay) if delay > request.retry.max_delay => None,
+            RetryDecision::After(delay) => Some(delay),
+        };
+        let Some(delay) = delay else {
+            result.error = Some(error);
+            return result;
+        };
+        tracing::warn!(
+            error = error.to_string(),
+            attempts,
+            delay = tracing::field::debug(&delay),
+            "Chunk download error, retry"
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
+/// Read the response body chunk by chunk, verifying each chunk at its boundary and writing
+/// it in position before the next one arrives. Chunks failing verification are skipped.
+async fn receive_chunks(
+    mut response: Response,
+    request: &DownloadRangeRequest,
+    result: &mut SpanResult,
+) -> Result<(), Error> {
+    let mut buffer = BytesMut::new();
+    for index in request.first_chunk + result.received()..request.chunks().end {
+        let start = index * request.chunk_size;
+        let length = (u64::min(start + request.chunk_size, request.end + 1) - start) as usize;
+        while buffer.len() < length {
+            match response.chunk().await.map_err(Error::Request)? {
+                Some(bytes) => {
+                    // Pace reads to the bandwidth limits, in total and for the provider
+                    request
+                        .throttle
+                        .acquire(&request.service.operator, bytes.len() as u64)
+                        .await;
+                    buffer.extend_from_slice(&bytes)
+                }
+                None => {
+                    return Err(Error::DataUnavailable(format!(
+                        "Response ended before chunk {} completed: {}",
+                        index, &request.query_endpoint
+                    )))
+                }
+            }
+        }
+        let data = buffer.split_to(length).freeze();
+        let chunk_hash = &request.chunk_hashes[(index - request.first_chunk) as usize];
+        if !verify_chunk(&data, chunk_hash) {
+            // Keep reading the span; the chunk is requested again from another provider
+            tracing::debug!(
+                chunk = index,
+                query_endpoint = &request.query_endpoint,
+                "Received invalid chunk"
+            );
+            result.invalid.push(index);
+            continue;
+        }
+        // Positional writes let chunks land concurrently, off the async runtime
+        let file = request.file.clone();
+        tokio::task::spawn_blocking(move || write_all_at(&file, &data, start))
+            .await
+            .map_err(|e| Error::DataUnavailable(e.to_string()))?
+            .map_err(Error::FileIOError)?;
+        result.verified.push(index);
     }
+    Ok(())
 }
 
-/// Make range request for a file to the bundle server
+/// Make range request for a file to the bundle server, returning the response to read the
+/// range from. Receipts are only signed once the request is about to be sent.
 pub async fn request_chunk(
     http_client: &Client,
-    query_endpoint: &str,
-    auth_header: (HeaderName, String),
-    file_hash: &str,
+    request: &DownloadRangeRequest,
+    payment: &PaymentMethod,
     start: u64,
-    end: u64,
-) -> Result<Bytes, Error> {
-    let range = format!("bytes={}-{}", start, end);
+    timeout: Duration,
+) -> Result<Response, Error> {
+    let query_endpoint = &request.query_endpoint;
+    let range = format!("bytes={}-{}", start, request.end);
+    let auth_header = payment
+        .header(&request.service, request.end - start + 1)
+        .await?;
+    // The file service only sees the request body, so the receipt or free query token is
+    // repeated there to be checked
+    let (receipt, authorization) = match payment {
+        PaymentMethod::FreeQuery(_) => (None, Some(auth_header.1.clone())),
+        PaymentMethod::PaidQuery(_) => (Some(auth_header.1.clone()), None),
+    };
 
     // indexer framework enforced that only authorization header is effective.
     // we move file_hash and content-range to body, but consider requesting indexer-framework to be more flexible
 
     let req_body = serde_json::json!({
-        "file-hash": file_hash,
+        "file-hash": request.file_hash,
         "content-range": range,
+        "receipt": receipt,
+        "authorization": authorization,
     }
     );
 
@@ -106,24 +242,150 @@ pub async fn request_chunk(
         .post(query_endpoint)
         .header(auth_header.0, auth_header.1)
         .json(&req_body)
+        .timeout(timeout)
         .send()
         .await
         .map_err(Error::Request)?;
 
     // Check if the server supports range requests
     if response.status().is_success() {
-        Ok(response.bytes().await.map_err(Error::Request)?)
+        Ok(response)
     } else {
-        let err_msg = format!(
-            "Server does not support range requests or the request failed: {:#?}",
-            tracing::field::debug(&response.status()),
-        );
         tracing::error!(
             status = tracing::field::debug(&response.status()),
             headers = tracing::field::debug(&response.headers()),
             chunk = tracing::field::debug(&response),
             "Server does not support range requests or the request failed"
         );
-        Err(Error::InvalidRange(err_msg))
+        Err(Error::ResponseStatus(
+            response.status(),
+            retry_after(response.headers()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::file_hasher::hash_chunk;
+    use reqwest::StatusCode;
+    use std::io::{Read, Seek};
+    use tempfile::tempfile;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn range_request(server: &MockServer, chunks: &[&[u8]]) -> DownloadRangeRequest {
+        let total_bytes: usize = chunks.iter().map(|c| c.len()).sum();
+        DownloadRangeRequest {
+            service: ServiceEndpoint {
+                operator: "0x0".to_string(),
+                service_endpoint: server.uri(),
+                deployment: "QmBundle".to_string(),
+                price_per_byte: 1.0,
+            },
+            query_endpoint: format!("{}/files/id/QmBundle", server.uri()),
+            file_hash: "QmFile".to_string(),
+            start: 0,
+            end: total_bytes as u64 - 1,
+            first_chunk: 0,
+            chunk_size: chunks[0].len() as u64,
+            chunk_hashes: chunks.iter().map(|c| hash_chunk(c)).collect(),
+            file: Arc::new(tempfile().unwrap()),
+            retry: RetryPolicy {
+                max_retry: 3,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(10),
+                timeout: Duration::from_secs(5),
+            },
+            throttle: Arc::new(Throttle::default()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_unavailable() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/files/id/QmBundle"))
+            .respond_with(ResponseTemplate::new(503).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/files/id/QmBundle"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"chunk".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let request = range_request(&server, &[b"chunk"]);
+        let payment = PaymentMethod::FreeQuery("token".to_string());
+        let result = download_chunk_and_write_to_file(&Client::new(), request, &payment).await;
+        assert!(result.error.is_none());
+        assert_eq!(result.verified, vec![0]);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_on_payment_rejection() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(402))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let request = range_request(&server, &[b"chunk"]);
+        let payment = PaymentMethod::FreeQuery("token".to_string());
+        let result = download_chunk_and_write_to_file(&Client::new(), request, &payment).await;
+        assert!(result.verified.is_empty());
+        assert!(matches!(
+            result.error,
+            Some(Error::ResponseStatus(StatusCode::PAYMENT_REQUIRED, None))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_span_skips_invalid_chunk() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(206).set_body_bytes(b"aaaaxxxxcc".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let request = range_request(&server, &[b"aaaa", b"bbbb", b"cc"]);
+        let file = request.file.clone();
+        let payment = PaymentMethod::FreeQuery("token".to_string());
+        let result = download_chunk_and_write_to_file(&Client::new(), request, &payment).await;
+        assert_eq!(result.verified, vec![0, 2]);
+        assert_eq!(result.invalid, vec![1]);
+        assert!(matches!(result.error, Some(Error::ChunkInvalid(_))));
+
+        // Only verified chunks are written
+        let mut contents = vec![];
+        let mut file = file.as_ref();
+        file.rewind().unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"aaaa\0\0\0\0cc");
+    }
+
+    #[test]
+    fn test_merge_span_results() {
+        let partial = SpanResult {
+            verified: vec![0, 2],
+            invalid: vec![1],
+            error: Some(Error::ChunkInvalid("1".to_string())),
+        };
+        let complete = SpanResult {
+            verified: vec![0, 1, 2],
+            invalid: vec![],
+            error: None,
+        };
+        let merged = partial.merge(complete);
+        assert_eq!(merged.verified, vec![0, 1, 2]);
+        assert!(merged.invalid.is_empty());
+        assert!(merged.error.is_none());
     }
 }
@@ -0,0 +1,134 @@
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
+use std::time::Duration;
+use tokio_retry::strategy::{jitter, ExponentialBackoff};
+
+use crate::{config::DownloaderArgs, errors::Error};
+
+/// Timeout and backoff schedule of the requests for a chunk
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retry: u64,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub timeout: Duration,
+}
+
+/// How a failed chunk request is handled
+#[derive(Debug, PartialEq)]
+pub enum RetryDecision {
+    /// Retrying cannot succeed, such as a rejected payment or an unsatisfiable range
+    Never,
+    /// Transient failure, retry after an exponential backoff with jitter
+    Backoff,
+    /// The provider asked to retry after a delay
+    After(Duration),
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &DownloaderArgs) -> Self {
+        let base_delay = Duration::from_millis(config.retry_base_delay_ms.max(1));
+        RetryPolicy {
+            max_retry: config.max_retry.max(1),
+            base_delay,
+            max_delay: Duration::from_millis(config.retry_max_delay_ms).max(base_delay),
+            timeout: Duration::from_secs(config.chunk_timeout.max(1)),
+        }
+    }
+
+    /// Delays between attempts, doubling from the base delay up to the max delay, each
+    /// randomized so retries from concurrent chunks spread out
+    pub fn backoff(&self) -> impl Iterator<Item = Duration> {
+        let base_ms = self.base_delay.as_millis() as u64;
+        ExponentialBackoff::from_millis(2)
+            .factor((base_ms + 1) / 2)
+            .max_delay(self.max_delay)
+            .map(jitter)
+    }
+}
+
+/// Classify a failed chunk request by its error
+pub fn classify(error: &Error) -> RetryDecision {
+    match error {
+        Error::ResponseStatus(status, retry_after) => match *status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                retry_after.map_or(RetryDecision::Backoff, RetryDecision::After)
+            }
+            s if s.is_server_error() => RetryDecision::Backoff,
+            // Authorization and payment failures, and other client errors, repeat on retry
+            _ => RetryDecision::Never,
+        },
+        // Connection resets and timeouts
+        Error::Request(e) if !e.is_builder() => RetryDecision::Backoff,
+        _ => RetryDecision::Never,
+    }
+}
+
+/// Parse a `Retry-After` header given in seconds
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_classify_status() {
+        let status = |code: u16, after: Option<Duration>| {
+            classify(&Error::ResponseStatus(
+                StatusCode::from_u16(code).unwrap(),
+                after,
+            ))
+        };
+        assert_eq!(status(401, None), RetryDecision::Never);
+        assert_eq!(status(402, None), RetryDecision::Never);
+        assert_eq!(status(416, None), RetryDecision::Never);
+        assert_eq!(status(500, None), RetryDecision::Backoff);
+        assert_eq!(status(503, None), RetryDecision::Backoff);
+        assert_eq!(
+            status(429, Some(Duration::from_secs(3))),
+            RetryDecision::After(Duration::from_secs(3))
+        );
+        assert_eq!(
+            classify(&Error::ChunkInvalid("hash".to_string())),
+            RetryDecision::Never
+        );
+    }
+
+    #[test]
+    fn test_retry_after_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn test_backoff_is_bounded() {
+        let policy = RetryPolicy {
+            max_retry: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            timeout: Duration::from_secs(1),
+        };
+        for delay in policy.backoff().take(10) {
+            assert!(delay <= Duration::from_millis(1000));
+        }
+    }
+}
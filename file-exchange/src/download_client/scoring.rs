@@ -0,0 +1,146 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex as StdMutex;
+use std::time::Duration;
+
+use crate::{config::SelectionPolicy, discover::ServiceEndpoint};
+
+/// Weight of the latest observation in the moving averages
+const EWMA_ALPHA: f64 = 0.2;
+
+/// Live statistics of chunk requests made to a provider
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProviderStats {
+    pub requests: u64,
+    pub errors: u64,
+    pub invalid_chunks: u64,
+    pub bytes: u64,
+    /// Moving average of request latency in milliseconds
+    pub latency_ms: Option<f64>,
+    /// Moving average of throughput in bytes per second
+    pub throughput: Option<f64>,
+}
+
+fn ewma(average: Option<f64>, value: f64) -> f64 {
+    match average {
+        Some(a) => a + EWMA_ALPHA * (value - a),
+        None => value,
+    }
+}
+
+impl ProviderStats {
+    pub fn record_success(&mut self, bytes: u64, elapsed: Duration) {
+        self.requests += 1;
+        self.bytes += bytes;
+        let secs = elapsed.as_secs_f64().max(1e-6);
+        self.latency_ms = Some(ewma(self.latency_ms, secs * 1000.0));
+        self.throughput = Some(ewma(self.throughput, bytes as f64 / secs));
+    }
+
+    pub fn record_error(&mut self) {
+        self.requests += 1;
+        self.errors += 1;
+    }
+
+    pub fn record_invalid(&mut self) {
+        self.requests += 1;
+        self.invalid_chunks += 1;
+    }
+
+    /// Share of requests that succeeded, smoothed so a single early failure is not fatal
+    pub fn reliability(&self) -> f64 {
+        let failures = (self.errors + self.invalid_chunks) as f64;
+        (self.requests as f64 - failures + 1.0) / (self.requests as f64 + 1.0)
+    }
+}
+
+/// Per-provider statistics keyed by service endpoint, used to weight chunk assignment
+#[derive(Debug, Default)]
+pub struct ProviderScores {
+    stats: StdMutex<HashMap<String, ProviderStats>>,
+}
+
+impl ProviderScores {
+    pub fn update(&self, service_endpoint: &str, f: impl FnOnce(&mut ProviderStats)) {
+        let mut stats = self.stats.lock().unwrap();
+        f(stats.entry(service_endpoint.to_string()).or_default());
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, ProviderStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Weight of each provider under a selection policy. Providers without throughput
+    /// measurements are assumed as fast as the fastest measured one, so they get tried.
+    pub fn weights(&self, policy: &SelectionPolicy, endpoints: &[ServiceEndpoint]) -> Vec<f64> {
+        let stats = self.stats.lock().unwrap();
+        let best_throughput = stats
+            .values()
+            .filter_map(|s| s.throughput)
+            .fold(1.0, f64::max);
+
+        endpoints
+            .iter()
+            .map(|endpoint| {
+                let provider = stats
+                    .get(&endpoint.service_endpoint)
+                    .cloned()
+                    .unwrap_or_default();
+                let throughput = provider.throughput.unwrap_or(best_throughput) / best_throughput;
+                // Guard free providers from dividing by zero
+                let cost = endpoint.price_per_byte.max(f64::EPSILON);
+                let weight = match policy {
+                    SelectionPolicy::Cheapest => 1.0 / cost,
+                    SelectionPolicy::Fastest => throughput,
+                    SelectionPolicy::Balanced => throughput / cost,
+                };
+                weight * provider.reliability()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(url: &str, price_per_byte: f64) -> ServiceEndpoint {
+        ServiceEndpoint {
+            operator: "0x0".to_string(),
+            service_endpoint: url.to_string(),
+            deployment: "Qm".to_string(),
+            price_per_byte,
+        }
+    }
+
+    #[test]
+    fn test_policy_weights() {
+        let scores = ProviderScores::default();
+        let endpoints = vec![endpoint("cheap", 1.0), endpoint("fast", 2.0)];
+        scores.update("cheap", |s| {
+            s.record_success(1000, Duration::from_secs(1));
+        });
+        scores.update("fast", |s| {
+            s.record_success(10_000, Duration::from_secs(1));
+        });
+
+        let cheapest = scores.weights(&SelectionPolicy::Cheapest, &endpoints);
+        assert!(cheapest[0] > cheapest[1]);
+        let fastest = scores.weights(&SelectionPolicy::Fastest, &endpoints);
+        assert!(fastest[1] > fastest[0]);
+        let balanced = scores.weights(&SelectionPolicy::Balanced, &endpoints);
+        assert!(balanced[1] > balanced[0]);
+    }
+
+    #[test]
+    fn test_failures_lower_weight() {
+        let scores = ProviderScores::default();
+        let endpoints = vec![endpoint("a", 1.0), endpoint("b", 1.0)];
+        scores.update("a", |s| s.record_error());
+        scores.update("a", |s| s.record_invalid());
+
+        let weights = scores.weights(&SelectionPolicy::Balanced, &endpoints);
+        assert!(weights[0] < weights[1]);
+        assert!(weights[0] > 0.0);
+    }
+}
@@ -1,3 +1,4 @@
+use std::str::FromStr;
 use std::time::SystemTime;
 
 use alloy_primitives::{Address, U256};
@@ -37,13 +38,7 @@ impl ReceiptSigner {
     pub async fn new(signer: SecretKey, chain_id: U256, verifier: Address) -> Self {
         Self {
             signer,
-            domain: Eip712Domain {
-                name: Some("TAP".into()),
-                version: Some("1".into()),
-                chain_id: Some(chain_id),
-                verifying_contract: Some(verifier),
-                salt: None,
-            },
+            domain: receipt_domain(chain_id, verifier),
         }
     }
 
@@ -63,7 +58,10 @@ impl ReceiptSigner {
             allocation_id,
             timestamp_ns,
             nonce,
-            value: fee.0.as_u128().unwrap_or(0),
+            value: fee.wei().ok_or(Error::PricingError(format!(
+                "Receipt value out of range: {:?}",
+                fee
+            )))?,
         };
         let wallet = Wallet::from_bytes(self.signer.as_ref()).map_err(Error::WalletError)?;
         EIP712SignedMessage::new(&self.domain, receipt, &wallet)
@@ -73,6 +71,30 @@ impl ReceiptSigner {
     }
 }
 
+/// EIP-712 domain of receipts for the chain and the receipts verifier contract
+pub fn receipt_domain(chain_id: U256, verifier: Address) -> Eip712Domain {
+    Eip712Domain {
+        name: Some("TAP".into()),
+        version: Some("1".into()),
+        chain_id: Some(chain_id),
+        verifying_contract: Some(verifier),
+        salt: None,
+    }
+}
+
+/// Recover the address that signed a receipt under the domain of the chain and verifier
+pub fn recover_signer(
+    receipt: &TapReceipt,
+    chain_id: u64,
+    verifier: &str,
+) -> Result<Address, Error> {
+    let verifier = Address::from_str(verifier)
+        .map_err(|e| Error::InvalidConfig(format!("Invalid receipts verifier: {}", e)))?;
+    receipt
+        .recover_signer(&receipt_domain(U256::from(chain_id), verifier))
+        .map_err(|e| Error::ContractError(e.to_string()))
+}
+
 pub enum ScalarReceipt {
     TAP(EIP712SignedMessage<Receipt>),
 }
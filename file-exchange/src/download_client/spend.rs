@@ -0,0 +1,157 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex as StdMutex;
+
+use crate::{
+    errors::Error,
+    util::{SpendTotals, GRT},
+};
+
+/// Value of receipts issued by a download against an optional budget. Totals carry over from
+/// the progress file of previous runs, so a resumed download is held to the same budget.
+#[derive(Debug)]
+pub struct Spending {
+    budget: Option<GRT>,
+    /// Spent by previous runs of the download
+    previous: SpendTotals,
+    /// Spent by this run
+    run: StdMutex<SpendTotals>,
+    /// Set once a receipt is refused for going over the budget
+    exhausted: AtomicBool,
+}
+
+/// Value of receipts issued, by this run and in total
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SpendReport {
+    /// Spent by this run
+    pub run: GRT,
+    /// Spent by this run and the previous runs of the download
+    pub total: GRT,
+    pub budget: Option<GRT>,
+    /// Spent by this run per provider service endpoint
+    pub providers: HashMap<String, GRT>,
+}
+
+impl Spending {
+    pub fn new(budget: Option<GRT>, previous: SpendTotals) -> Self {
+        let exhausted = budget.is_some_and(|budget| previous.total >= budget);
+        Spending {
+            budget,
+            previous,
+            run: StdMutex::new(SpendTotals::default()),
+            exhausted: AtomicBool::new(exhausted),
+        }
+    }
+
+    /// Account for a receipt to a provider before it is issued. A receipt that would take the
+    /// total over the budget is refused and no further receipts are expected.
+    pub fn try_spend(&self, provider: &str, value: GRT) -> Result<(), Error> {
+        let mut run = self.run.lock().unwrap();
+        let total = GRT(self
+            .previous
+            .total
+            .0
+            .saturating_add(run.total.0)
+            .saturating_add(value.0));
+        if let Some(budget) = self.budget {
+            if total > budget {
+                self.exhausted.store(true, Ordering::SeqCst);
+                return Err(Error::BudgetExceeded(format!(
+                    "Receipt of {} GRT would take the spend over the budget of {} GRT",
+                    value.0, budget.0
+                )));
+            }
+        }
+        run.total = GRT(run.total.0.saturating_add(value.0));
+        let spent = run.providers.entry(provider.to_string()).or_default();
+        *spent = GRT(spent.0.saturating_add(value.0));
+        Ok(())
+    }
+
+    /// Take back a receipt accounted for but never issued
+    pub fn refund(&self, provider: &str, value: GRT) {
+        let mut run = self.run.lock().unwrap();
+        run.total = GRT(run.total.0.saturating_sub(value.0));
+        if let Some(spent) = run.providers.get_mut(provider) {
+            *spent = GRT(spent.0.saturating_sub(value.0));
+        }
+    }
+
+    /// Whether the budget is used up, so no more requests should be made
+    pub fn exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::SeqCst)
+    }
+
+    /// Totals of this run and the previous runs, to be stored in the progress file
+    pub fn totals(&self) -> SpendTotals {
+        let run = self.run.lock().unwrap();
+        let mut totals = self.previous.clone();
+        totals.total = GRT(totals.total.0.saturating_add(run.total.0));
+        for (provider, spent) in &run.providers {
+            let total = totals.providers.entry(provider.clone()).or_default();
+            *total = GRT(total.0.saturating_add(spent.0));
+        }
+        totals
+    }
+
+    pub fn report(&self) -> SpendReport {
+        let run = self.run.lock().unwrap().clone();
+        SpendReport {
+            run: run.total,
+            total: GRT(self.previous.total.0.saturating_add(run.total.0)),
+            budget: self.budget,
+            providers: run.providers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grt(value: f64) -> GRT {
+        GRT::from_decimal(value).unwrap()
+    }
+
+    #[test]
+    fn test_spend_within_budget() {
+        let previous = SpendTotals {
+            total: grt(0.5),
+            providers: HashMap::from([("a".to_string(), grt(0.5))]),
+        };
+        let spending = Spending::new(Some(grt(1.0)), previous);
+        spending.try_spend("a", grt(0.25)).unwrap();
+        spending.try_spend("b", grt(0.2)).unwrap();
+        // Over the budget, counting the previous runs
+        assert!(matches!(
+            spending.try_spend("b", grt(0.1)),
+            Err(Error::BudgetExceeded(_))
+        ));
+        assert!(spending.exhausted());
+
+        let report = spending.report();
+        assert_eq!(report.run, grt(0.45));
+        assert_eq!(report.total, grt(0.95));
+        assert_eq!(report.providers["b"], grt(0.2));
+        let totals = spending.totals();
+        assert_eq!(totals.total, grt(0.95));
+        assert_eq!(totals.providers["a"], grt(0.75));
+    }
+
+    #[test]
+    fn test_refund_and_unlimited_budget() {
+        let spending = Spending::new(None, SpendTotals::default());
+        spending.try_spend("a", grt(3.0)).unwrap();
+        spending.refund("a", grt(1.0));
+        assert_eq!(spending.report().providers["a"], grt(2.0));
+        assert!(!spending.exhausted());
+
+        // A budget already used up by previous runs refuses any request
+        let previous = SpendTotals {
+            total: grt(1.0),
+            providers: HashMap::new(),
+        };
+        assert!(Spending::new(Some(grt(1.0)), previous).exhausted());
+    }
+}
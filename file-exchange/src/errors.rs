@@ -10,6 +10,7 @@ pub enum Error {
     IPFSError(reqwest::Error),
     ManifestError(String),
     Request(reqwest::Error),
+    ResponseStatus(reqwest::StatusCode, Option<std::time::Duration>),
     DataUnavailable(String),
     ChunkInvalid(String),
     ServerError(ServerError),
@@ -21,6 +22,7 @@ pub enum Error {
     ContractError(String),
     ObjectStoreError(object_store::Error),
     WalletError(ethers::signers::WalletError),
+    BudgetExceeded(String),
 }
 
 impl fmt::Display for Error {
@@ -32,6 +34,9 @@ impl fmt::Display for Error {
             Error::IPFSError(ref err) => write!(f, "IPFS error: {}", err),
             Error::ManifestError(ref msg) => write!(f, "Manifest error: {}", msg),
             Error::Request(ref err) => write!(f, "Client error: {}", err),
+            Error::ResponseStatus(ref status, _) => {
+                write!(f, "Request failed with status: {}", status)
+            }
             Error::DataUnavailable(ref err) => write!(f, "Client error: {}", err),
             Error::ChunkInvalid(ref err) => write!(f, "Chunk invalid error: {}", err),
             Error::ServerError(ref err) => write!(f, "Server error: {}", err),
@@ -43,6 +48,7 @@ impl fmt::Display for Error {
             Error::ContractError(ref msg) => write!(f, "Contract call error: {}", msg),
             Error::ObjectStoreError(ref err) => write!(f, "Object store error: {}", err),
             Error::WalletError(ref err) => write!(f, "Wallet error: {}", err),
+            Error::BudgetExceeded(ref msg) => write!(f, "Spend budget exceeded: {}", msg),
         }
     }
 }
@@ -18,7 +18,7 @@ struct EscrowStatus {
     escrow_accounts: Vec<EscrowAccount>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ID {
     pub id: String,
@@ -48,13 +48,13 @@ pub async fn escrow_accounts(
     Ok(result?.escrow_accounts)
 }
 
-// Query escrow accounts related to the sender and receiver from the escrow subgraph
-pub async fn escrow_balance(
+// Query the escrow account of the sender with the receiver from the escrow subgraph
+pub async fn escrow_account(
     graphql_client: &reqwest::Client,
     escrow_subgraph: &str,
     sender: &str,
     receiver: &str,
-) -> Result<Option<f64>, Error> {
+) -> Result<Option<EscrowAccount>, Error> {
     let query = r#"query account($sender: ID!, $receiver: ID!) { escrowAccounts(where: {sender: $sender, receiver: $receiver}) { sender { id } receiver { id } balance } }"#;
     let result = graphql_query::<EscrowStatus>(
         graphql_client,
@@ -66,18 +66,17 @@ pub async fn escrow_balance(
     )
     .await?;
     result
-        .map(|status| {
-            if status.escrow_accounts.is_empty() {
-                None
-            } else {
-                status
-                    .escrow_accounts
-                    .first()
-                    .unwrap()
-                    .balance
-                    .parse::<f64>()
-                    .ok()
-            }
-        })
+        .map(|status| status.escrow_accounts.into_iter().next())
         .map_err(Error::GraphQLResponseError)
 }
+
+// Query escrow accounts related to the sender and receiver from the escrow subgraph
+pub async fn escrow_balance(
+    graphql_client: &reqwest::Client,
+    escrow_subgraph: &str,
+    sender: &str,
+    receiver: &str,
+) -> Result<Option<f64>, Error> {
+    let account = escrow_account(graphql_client, escrow_subgraph, sender, receiver).await?;
+    Ok(account.and_then(|account| account.balance.parse::<f64>().ok()))
+}
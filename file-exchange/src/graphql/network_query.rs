@@ -1,8 +1,8 @@
 use serde::Deserialize;
 
-use crate::graphql::graphql_query;
+use crate::{errors::Error, graphql::graphql_query};
 
-use super::Query;
+use super::{escrow_query::ID, Query};
 
 // Query current epoch from network subgraph
 pub async fn current_epoch(
@@ -36,3 +36,98 @@ pub async fn current_epoch(
         .ok_or_else(|| anyhow::anyhow!("Network {} not found", graph_network_id))
         .map(|network| network.current_epoch)
 }
+
+/// Active allocation of an indexer on a deployment
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Allocation {
+    pub id: String,
+    pub indexer: AllocatedIndexer,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocatedIndexer {
+    pub id: String,
+    /// Service URL registered by the indexer, if any
+    pub url: Option<String>,
+    #[serde(default)]
+    pub account: Option<IndexerAccount>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexerAccount {
+    /// Operators authorized to act for the indexer
+    pub operators: Vec<ID>,
+}
+
+// Query the active allocations on a deployment, identified by its IPFS hash, from network subgraph
+// Most recent allocations come first
+pub async fn active_allocations(
+    graphql_client: &reqwest::Client,
+    network_subgraph: &str,
+    deployment_ipfs: &str,
+) -> Result<Vec<Allocation>, Error> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct AllocationsData {
+        allocations: Vec<Allocation>,
+    }
+
+    let query = r#"query allocations($deployment: String!) { allocations(first: 1000, orderBy: createdAt, orderDirection: desc, where: {status: Active, subgraphDeployment_: {ipfsHash: $deployment}}) { id indexer { id url account { operators { id } } } } }"#;
+    let result = graphql_query::<AllocationsData>(
+        graphql_client,
+        network_subgraph,
+        Query::new_with_variables(query, [("deployment", deployment_ipfs.into())]),
+    )
+    .await?;
+
+    result
+        .map(|data| data.allocations)
+        .map_err(Error::GraphQLResponseError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    async fn test_active_allocations() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_string_contains("QmBundle"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {
+                    "allocations": [
+                        {
+                            "id": "0xa1",
+                            "indexer": { "id": "0xi1", "url": "http://indexer-1.xyz" }
+                        },
+                        {
+                            "id": "0xa2",
+                            "indexer": { "id": "0xi2", "url": null }
+                        }
+                    ]
+                }
+            })))
+            .mount(&server)
+            .await;
+
+        let allocations = active_allocations(&reqwest::Client::new(), &server.uri(), "QmBundle")
+            .await
+            .unwrap();
+        assert_eq!(allocations.len(), 2);
+        assert_eq!(allocations[0].id, "0xa1");
+        assert_eq!(
+            allocations[0].indexer.url.as_deref(),
+            Some("http://indexer-1.xyz")
+        );
+        assert_eq!(allocations[1].indexer.url, None);
+    }
+}
@@ -6,5 +6,6 @@ pub mod graphql;
 pub mod manifest;
 pub mod publisher;
 pub mod test_util;
+pub mod throttle;
 pub mod transaction_manager;
 pub mod util;
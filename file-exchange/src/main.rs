@@ -2,12 +2,13 @@ use dotenv::dotenv;
 
 use file_exchange::{
     config::{Cli, OnchainAction, Role},
-    download_client::Downloader,
+    discover::{Finder, DEFAULT_STATUS_TTL},
+    download_client::{blocklist_path, Downloader},
     graphql::network_query::current_epoch,
     manifest::ipfs::IpfsClient,
     publisher::ManifestPublisher,
     transaction_manager::TransactionManager,
-    util::store_map_as_json,
+    util::{store_progress, ProgressCache},
 };
 
 #[tokio::main]
@@ -32,14 +33,26 @@ async fn main() {
 
             if let Some(cache) = progress_file {
                 let chunks = downloader.target_chunks.clone();
+                let blocklist = downloader.blocklist();
+                let spending = downloader.spending();
                 ctrlc::set_handler(move || {
                     tracing::info!("CTRL+C pressed. Store progress cache to json");
 
-                    let map = chunks.lock().unwrap();
-                    match store_map_as_json(&map, &cache) {
+                    let progress = ProgressCache {
+                        chunks: chunks.lock().unwrap().clone(),
+                        spend: spending.totals(),
+                    };
+                    tracing::info!(
+                        spend = tracing::field::debug(spending.report()),
+                        "Receipts issued"
+                    );
+                    match store_progress(&progress, &cache) {
                         Ok(_) => println!("Data successfully saved"),
                         Err(e) => eprintln!("Failed to save progress: {}", e),
                     }
+                    if let Err(e) = blocklist.save(&blocklist_path(&cache)) {
+                        eprintln!("Failed to save blocklist: {}", e);
+                    }
 
                     std::process::exit(0); // Exit the process
                 })
@@ -56,6 +69,25 @@ async fn main() {
                 }
             }
         }
+        Role::Discover(config) => {
+            tracing::info!(config = tracing::field::debug(&config), "Discover request");
+
+            let finder =
+                Finder::new(client).with_limits(config.discovery_concurrency, DEFAULT_STATUS_TTL);
+            match finder
+                .bundle_compatibilities(&config.ipfs_hash, &config.indexer_endpoints)
+                .await
+            {
+                Ok(compatibilities) => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&compatibilities)
+                        .expect("Serialize bundle compatibilities")
+                ),
+                Err(e) => {
+                    tracing::error!(error = e.to_string(), "Failed to discover bundles");
+                }
+            }
+        }
         Role::Publisher(config) => {
             tracing::info!(config = tracing::field::debug(&config), "Publisher request");
 
@@ -72,6 +72,7 @@ mod tests {
 
         let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: readdir1.to_string(),
+            ..Default::default()
         }))
         .unwrap();
         // produce the same file manifest
@@ -107,6 +108,7 @@ mod tests {
         // produce different file manifest
         let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: readdir1.to_string(),
+            ..Default::default()
         }))
         .unwrap();
         let file_manifest1 = store
@@ -137,6 +139,7 @@ mod tests {
         // produce the same file manifest
         let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: readdir.to_string(),
+            ..Default::default()
         }))
         .unwrap();
         let file_manifest1 = store
@@ -165,6 +168,7 @@ mod tests {
 
         let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: readdir1.to_string(),
+            ..Default::default()
         }))
         .unwrap();
         let bytes_vec = store
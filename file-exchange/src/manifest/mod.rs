@@ -1,5 +1,4 @@
 use async_graphql::SimpleObject;
-use object_store::path::Path;
 
 pub mod file_hasher;
 pub mod file_reader;
@@ -111,7 +110,7 @@ pub struct BlockRange {
 // }
 
 /// Validate the bundle configurations at initialization
-pub fn validate_bundle_entries(entries: Vec<String>) -> Result<Vec<(String, Path)>, Error> {
+pub fn validate_bundle_entries(entries: Vec<String>) -> Result<Vec<(String, String)>, Error> {
     let mut results = Vec::new();
 
     for entry in entries {
@@ -121,26 +120,22 @@ pub fn validate_bundle_entries(entries: Vec<String>) -> Result<Vec<(String, Path
     Ok(results)
 }
 
-/// Bundle entry must be in the format of "valid_ipfs_hash:valid_local_path"
-pub fn validate_bundle_entry(entry: String) -> Result<(String, Path), Error> {
-    let parts: Vec<&str> = entry.split(':').collect();
-    if parts.len() != 2 {
-        return Err(Error::InvalidConfig(format!(
-            "Invalid format for entry: {}",
-            entry
-        )));
-    }
+/// Bundle entry must be in the format of "valid_ipfs_hash:location", where location is either
+/// a prefix in the main storage or a storage spec such as "s3://bucket/prefix?profile=name"
+pub fn validate_bundle_entry(entry: String) -> Result<(String, String), Error> {
+    let (ipfs_hash, location) = entry.split_once(':').ok_or(Error::InvalidConfig(format!(
+        "Invalid format for entry: {}",
+        entry
+    )))?;
 
-    let ipfs_hash = parts[0];
-    let local_path = parts[1];
-    validate_bundle_and_location(ipfs_hash, local_path)
+    validate_bundle_and_location(ipfs_hash, location)
 }
 
-// Check for valid ipfs hash and path
+// Check for valid ipfs hash; the location is resolved into a storage backend by the server
 pub fn validate_bundle_and_location(
     ipfs_hash: &str,
-    local_path: &str,
-) -> Result<(String, Path), Error> {
+    location: &str,
+) -> Result<(String, String), Error> {
     if !is_valid_ipfs_hash(ipfs_hash) {
         return Err(Error::InvalidConfig(format!(
             "Invalid IPFS hash: {}",
@@ -148,7 +143,5 @@ pub fn validate_bundle_and_location(
         )));
     }
 
-    // Validate local path
-
-    Ok((ipfs_hash.to_string(), Path::from(local_path)))
+    Ok((ipfs_hash.to_string(), location.to_string()))
 }
@@ -1,8 +1,10 @@
 use bytes::Bytes;
+use futures::{stream, StreamExt, TryStreamExt};
+use object_store::aws::{AmazonS3, AmazonS3Builder};
 use object_store::local::LocalFileSystem;
-use object_store::{parse_url_opts, path::Path, ObjectStore};
+use object_store::multipart::MultiPartStore;
+use object_store::{path::Path, ClientOptions, ObjectStore};
 use object_store::{ObjectMeta, PutResult};
-use reqwest::Url;
 use tokio::io::AsyncWriteExt;
 
 use std::fs::{self, File};
@@ -10,39 +12,65 @@ use std::ops::Range;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::config::{ObjectStoreArgs, StorageMethod};
+use crate::config::{ObjectStoreArgs, StorageMethod, StoreOptions};
 use crate::manifest::{verify_chunk, Error, FileManifestMeta, LocalBundle};
 
 use super::file_hasher::hash_chunk;
 use super::FileManifest;
 
+/// S3 (and compatible services) reject multipart uploads with more than 10,000 parts
+pub const MAX_MULTIPART_PARTS: usize = 10_000;
+
+/// Location of a file under a prefix in a store; an empty prefix is the store root
+fn file_location(file_name: &str, prefix: &Path) -> Path {
+    prefix.child(file_name)
+}
+
 #[derive(Debug, Clone)]
 pub struct Store {
     store: Arc<Box<dyn ObjectStore>>,
+    // Separate handle on S3 stores for uploading parts with explicit sizes
+    s3_multipart: Option<Arc<AmazonS3>>,
     pub storage_method: StorageMethod,
-    pub read_concurrency: usize,
-    pub write_concurrency: usize,
+    pub options: StoreOptions,
 }
 
 impl Store {
     /// Create a new store
     pub fn new(storage_args: &StorageMethod) -> Result<Self, Error> {
-        let store = match &storage_args {
-            StorageMethod::LocalFiles(directory) => Store::local_store(&directory.main_dir),
+        let options = storage_args.store_options().clone();
+        if options.read_concurrency == 0 || options.write_concurrency == 0 {
+            return Err(Error::InvalidConfig(
+                "Store read and write concurrency must be greater than 0".to_string(),
+            ));
+        }
+        if options.min_part_size == 0 || options.min_part_size > options.max_part_size {
+            return Err(Error::InvalidConfig(format!(
+                "Store part sizes must satisfy 0 < min ({}) <= max ({})",
+                options.min_part_size, options.max_part_size
+            )));
+        }
+
+        let (store, s3_multipart) = match &storage_args {
+            StorageMethod::LocalFiles(directory) => {
+                (Store::local_store(&directory.main_dir)?, None)
+            }
             StorageMethod::ObjectStorage(store_args) => {
                 fs::create_dir_all("tmp/".to_owned() + &store_args.bucket)
                     .expect("Failed to create temporary directory at /tmp");
-                Store::s3_store(store_args)
+                let builder = Store::s3_builder(store_args);
+                let multipart = builder.clone().build().map_err(Error::ObjectStoreError)?;
+                (Store::s3_store(store_args)?, Some(Arc::new(multipart)))
             }
-        }?;
+        };
 
         Ok(Store {
             store,
+            s3_multipart,
             storage_method: storage_args.clone(),
-            //TODO: Make configurable
-            read_concurrency: 16,
-            write_concurrency: 8,
+            options,
         })
     }
 
@@ -66,22 +94,36 @@ impl Store {
 
     /// Create a store connected to user's S3 bucket
     pub fn s3_store(store_config: &ObjectStoreArgs) -> Result<Arc<Box<dyn ObjectStore>>, Error> {
-        let endpoint = "s3://".to_string() + &store_config.bucket;
-        let url = Url::parse(&endpoint).unwrap();
-        // As long as the provided path is correct, the following should never panic
-        let (store, _): (Arc<Box<dyn ObjectStore>>, Path) = parse_url_opts(
-            &url,
-            vec![
-                ("region", &store_config.region),
-                ("endpoint", &store_config.endpoint),
-                ("bucket", &store_config.bucket),
-                ("aws_access_key_id", &store_config.access_key_id),
-                ("aws_secret_access_key", &store_config.secret_key),
-            ],
-        )
-        .map_err(Error::ObjectStoreError)
-        .map(|(s, p)| (Arc::new(s), p))?;
-        Ok(store)
+        let store = Store::s3_builder(store_config)
+            .build()
+            .map_err(Error::ObjectStoreError)?;
+        Ok(Arc::new(Box::new(store)))
+    }
+
+    /// S3 client builder with bucket credentials and request timeouts
+    fn s3_builder(store_config: &ObjectStoreArgs) -> AmazonS3Builder {
+        let options = &store_config.store_options;
+        AmazonS3Builder::new()
+            .with_url("s3://".to_string() + &store_config.bucket)
+            .with_region(&store_config.region)
+            .with_endpoint(&store_config.endpoint)
+            .with_bucket_name(&store_config.bucket)
+            .with_access_key_id(&store_config.access_key_id)
+            .with_secret_access_key(&store_config.secret_key)
+            .with_client_options(
+                ClientOptions::new()
+                    .with_timeout(Duration::from_secs(options.request_timeout))
+                    .with_connect_timeout(Duration::from_secs(options.connect_timeout)),
+            )
+    }
+
+    /// Part size for splitting an object of `size` bytes over `concurrency` requests,
+    /// bounded by the configured part sizes and the maximum number of multipart parts
+    pub fn part_size(&self, size: usize, concurrency: usize) -> usize {
+        let min_for_part_limit = (size + MAX_MULTIPART_PARTS - 1) / MAX_MULTIPART_PARTS;
+        (size / concurrency.max(1))
+            .max(min_for_part_limit)
+            .clamp(self.options.min_part_size, self.options.max_part_size)
     }
 
     /// List out all files in the path, optionally filtered by a prefix to the filesystem
@@ -96,23 +138,39 @@ impl Store {
 
     /// Find a specific object by file name with optional prefix
     pub async fn find_object(&self, file_name: &str, prefix: Option<&Path>) -> Option<ObjectMeta> {
-        let listed = self.list(prefix).await.unwrap();
-        listed
-            .iter()
-            .find(|obj| obj.location.to_string() == file_name)
-            .cloned()
+        let location = file_location(file_name, prefix.unwrap_or(&Path::from("")));
+        let listed = self.list(prefix).await.ok()?;
+        listed.into_iter().find(|obj| obj.location == location)
     }
 
-    pub async fn range_read(&self, file_name: &str, range: &Range<usize>) -> Result<Bytes, Error> {
-        Ok(self
-            .store
-            .get_range(&Path::from(file_name), range.to_owned())
+    /// Fetch object metadata (size, last modified, e_tag) of a file under a prefix
+    pub async fn object_meta(&self, file_name: &str, prefix: &Path) -> Result<ObjectMeta, Error> {
+        self.store
+            .head(&file_location(file_name, prefix))
+            .await
+            .map_err(Error::ObjectStoreError)
+    }
+
+    pub async fn range_read(
+        &self,
+        file_name: &str,
+        prefix: &Path,
+        range: &Range<usize>,
+    ) -> Result<Bytes, Error> {
+        self.store
+            .get_range(&file_location(file_name, prefix), range.to_owned())
             .await
-            .unwrap())
+            .map_err(Error::ObjectStoreError)
     }
 
-    pub async fn read(&self, location: &str) -> Result<File, Error> {
-        let result = match self.store.get(&Path::from(location)).await.unwrap().payload {
+    pub async fn read(&self, file_name: &str, prefix: &Path) -> Result<File, Error> {
+        let result = match self
+            .store
+            .get(&file_location(file_name, prefix))
+            .await
+            .map_err(Error::ObjectStoreError)?
+            .payload
+        {
             object_store::GetResultPayload::File(f, _p) => f,
             object_store::GetResultPayload::Stream(_) => {
                 return Err(Error::DataUnavailable(
@@ -137,30 +195,20 @@ impl Store {
                     "Did not find object {:?}",
                     file_path,
                 )))?;
-        let step = chunk_size.unwrap_or({
-            let s = object_meta.size / self.read_concurrency;
-            if s > 0 {
-                s
-            } else {
-                object_meta.size
-            }
-        });
+        let step = chunk_size
+            .unwrap_or_else(|| self.part_size(object_meta.size, self.options.read_concurrency));
         let ranges = (0..(object_meta.size / step + 1))
             .map(|i| std::ops::Range::<usize> {
                 start: i * step,
                 end: ((i + 1) * step).min(object_meta.size),
             })
             .collect::<Vec<std::ops::Range<usize>>>();
-        let location: Path = if let Some(prefix) = file_path {
-            prefix.child(file_name)
-        } else {
-            Path::from(file_name)
-        };
+        let location = file_location(file_name, file_path.unwrap_or(&Path::from("")));
         let result = self
             .store
             .get_ranges(&location, ranges.as_slice())
             .await
-            .unwrap();
+            .map_err(Error::ObjectStoreError)?;
 
         Ok(result)
     }
@@ -169,34 +217,82 @@ impl Store {
     pub async fn multipart_write(
         &self,
         location: &str,
-        bytes: &[u8],
+        bytes: Bytes,
         chunk_size: Option<usize>,
     ) -> Result<String, Error> {
+        let size = bytes.len();
+        let step = chunk_size
+            .unwrap_or_else(|| self.part_size(size, self.options.write_concurrency))
+            .max(1);
+        let part_count = (size + step - 1) / step;
+        if part_count > MAX_MULTIPART_PARTS {
+            return Err(Error::InvalidConfig(format!(
+                "Writing {} bytes in parts of {} bytes takes {} parts, exceeding the limit of {}; increase the maximum part size",
+                size, step, part_count, MAX_MULTIPART_PARTS
+            )));
+        }
+
+        if let Some(s3) = self.s3_multipart.as_ref().filter(|_| size > 0) {
+            return self.s3_multipart_write(s3, location, bytes, step).await;
+        }
+
         let (write_id, mut write) = self
             .store
             .put_multipart(&Path::from(location))
             .await
-            .unwrap();
-        let size = bytes.len();
-        let step = chunk_size.unwrap_or({
-            let s = size / self.write_concurrency;
-            if s > 0 {
-                s
-            } else {
-                size
-            }
-        });
+            .map_err(Error::ObjectStoreError)?;
 
-        for i in 0..(size / step + 1) {
-            let buf = &bytes[i * step..((i + 1) * step).min(size)];
-            write.write_all(buf).await.unwrap();
+        for buf in bytes.chunks(step) {
+            write.write_all(buf).await.map_err(Error::FileIOError)?;
         }
-        write.flush().await.unwrap();
-        write.shutdown().await.unwrap();
+        write.flush().await.map_err(Error::FileIOError)?;
+        write.shutdown().await.map_err(Error::FileIOError)?;
         drop(write);
         Ok(write_id)
     }
 
+    /// Upload parts of a fixed size to S3 with bounded concurrency, aborting the upload on failure
+    async fn s3_multipart_write(
+        &self,
+        s3: &Arc<AmazonS3>,
+        location: &str,
+        bytes: Bytes,
+        step: usize,
+    ) -> Result<String, Error> {
+        let path = Path::from(location);
+        let id = s3
+            .create_multipart(&path)
+            .await
+            .map_err(Error::ObjectStoreError)?;
+
+        // Parts are views into the shared buffer, sliced only as they are uploaded
+        let size = bytes.len();
+        let uploaded = stream::iter((0..size).step_by(step).enumerate())
+            .map(|(idx, start)| {
+                let data = bytes.slice(start..(start + step).min(size));
+                let (s3, path, id) = (s3.clone(), path.clone(), id.clone());
+                async move { s3.put_part(&path, &id, idx, data).await }
+            })
+            .buffered(self.options.write_concurrency)
+            .try_collect::<Vec<_>>()
+            .await;
+
+        let result = match uploaded {
+            Ok(part_ids) => s3.complete_multipart(&path, &id, part_ids).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            tracing::warn!(
+                location,
+                error = e.to_string(),
+                "Multipart upload failed, aborting"
+            );
+            let _ = s3.abort_multipart(&path, &id).await;
+            return Err(Error::ObjectStoreError(e));
+        }
+        Ok(id)
+    }
+
     /// Single write at a location path
     pub async fn write(&self, location: &str, bytes: &[u8]) -> Result<PutResult, Error> {
         self.store
@@ -299,7 +395,7 @@ impl Store {
                 end: end + 1,
             };
             let file_name = meta_info.name.clone();
-            let chunk_data = self.range_read(&file_name, &range).await?;
+            let chunk_data = self.range_read(&file_name, prefix, &range).await?;
             // verify chunk
             if !verify_chunk(&chunk_data, &chunk_hash) {
                 tracing::error!(
@@ -341,6 +437,7 @@ mod tests {
 
         let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: readdir.to_string(),
+            ..Default::default()
         }))
         .unwrap();
         let res = store.list(None).await.unwrap();
@@ -367,12 +464,13 @@ mod tests {
         // Write with adjusted concurrency
         let object_store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: directory.to_string(),
+            ..Default::default()
         }))
         .unwrap();
         let new_file_name = "tempfile";
         let test_bytes = test_string.as_bytes();
         let write_res = object_store
-            .multipart_write(new_file_name, test_bytes, None)
+            .multipart_write(new_file_name, Bytes::copy_from_slice(test_bytes), None)
             .await;
         assert!(write_res.is_ok());
 
@@ -387,12 +485,13 @@ mod tests {
         // Write with fixed concurrency
         let object_store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: directory.to_string(),
+            ..Default::default()
         }))
         .unwrap();
         let new_file_name = "tempfile";
         let test_bytes = test_string.as_bytes();
         let write_res = object_store
-            .multipart_write(new_file_name, test_bytes, None)
+            .multipart_write(new_file_name, Bytes::copy_from_slice(test_bytes), None)
             .await;
         assert!(write_res.is_ok());
 
@@ -408,6 +507,39 @@ mod tests {
         assert!(object_store.delete(new_file_name).await.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_part_size_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
+            main_dir: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        }))
+        .unwrap();
+        let options = StoreOptions::default();
+
+        // Small objects are read/written in a single minimum sized part
+        assert_eq!(store.part_size(10, 16), options.min_part_size);
+        // Large objects are capped by the maximum part size
+        let size = 100 * 1024 * 1024 * 1024;
+        assert_eq!(store.part_size(size, 8), options.max_part_size);
+        // High concurrency never produces more parts than the multipart limit allows
+        let step = store.part_size(60 * 1024 * 1024 * 1024, 100_000);
+        assert!(60 * 1024 * 1024 * 1024 / step < MAX_MULTIPART_PARTS);
+
+        // Explicit part sizes exceeding the part limit are rejected before writing
+        let bytes = Bytes::from(random_bytes(MAX_MULTIPART_PARTS * 2));
+        let res = store
+            .multipart_write("too_many_parts", bytes, Some(1))
+            .await;
+        assert!(res.is_err());
+        // Sizes that are an exact multiple of the part size take no extra part
+        let bytes = Bytes::from(random_bytes(MAX_MULTIPART_PARTS * 2));
+        let res = store
+            .multipart_write("exact_part_limit", bytes, Some(2))
+            .await;
+        assert!(res.is_ok());
+    }
+
     #[tokio::test]
     async fn test_find_example_file() {
         let main_directory = "../example-file";
@@ -415,6 +547,7 @@ mod tests {
         let file_name = "example-create-17686085.dbin";
         let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: main_directory.to_string(),
+            ..Default::default()
         }))
         .unwrap();
         let metadata = store.find_object(file_name, Some(&file_prefix)).await;
@@ -423,11 +556,43 @@ mod tests {
         assert!(metadata.is_some())
     }
 
+    #[tokio::test]
+    async fn test_prefixed_object() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
+            main_dir: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        }))
+        .unwrap();
+        let bytes = random_bytes(100);
+        store.write("bundle/data.bin", &bytes).await.unwrap();
+
+        // Reads of a file under a prefix resolve the prefixed location
+        let prefix = Path::from("bundle");
+        let found = store.find_object("data.bin", Some(&prefix)).await.unwrap();
+        assert_eq!(found.size, 100);
+        let meta = store.object_meta("data.bin", &prefix).await.unwrap();
+        assert_eq!(meta.location, found.location);
+        let data = store
+            .range_read("data.bin", &prefix, &(10..20))
+            .await
+            .unwrap();
+        assert_eq!(&data[..], &bytes[10..20]);
+
+        // The file is not at the store root
+        assert!(store.find_object("data.bin", None).await.is_none());
+        assert!(store
+            .range_read("data.bin", &Path::from(""), &(10..20))
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
     async fn test_read_and_validate_file() {
         let main_directory = "../example-file";
         let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: main_directory.to_string(),
+            ..Default::default()
         }))
         .unwrap();
         let mut bundle = simple_bundle();
@@ -453,6 +618,7 @@ mod tests {
         let main_directory = "../example-file";
         let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
             main_dir: main_directory.to_string(),
+            ..Default::default()
         }))
         .unwrap();
         let _local_path = Path::from("");
@@ -494,6 +660,7 @@ mod tests {
             access_key_id,
             secret_key,
             endpoint: s3_endpoint,
+            ..Default::default()
         };
 
         let store = Store::new(&StorageMethod::ObjectStorage(store_config)).unwrap();
@@ -521,6 +688,7 @@ mod tests {
             access_key_id,
             secret_key,
             endpoint: s3_endpoint,
+            ..Default::default()
         };
 
         let _main_directory = "../example-file";
@@ -532,7 +700,7 @@ mod tests {
         let res = store.delete(location).await;
         assert!(res.is_ok());
         let bytes = random_bytes(file_size.try_into().unwrap());
-        let res = store.multipart_write(location, &bytes, None).await;
+        let res = store.multipart_write(location, bytes.into(), None).await;
         assert!(res.is_ok());
         let res = store.delete(location).await;
         assert!(res.is_ok());
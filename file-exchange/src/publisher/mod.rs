@@ -152,6 +152,7 @@ mod tests {
         let args = PublisherArgs {
             storage_method: StorageMethod::LocalFiles(LocalDirectory {
                 main_dir: String::from("../example-file"),
+                ..Default::default()
             }),
             chunk_size: 1048576,
             ..Default::default()
@@ -172,6 +173,7 @@ mod tests {
         let args = PublisherArgs {
             storage_method: StorageMethod::LocalFiles(LocalDirectory {
                 main_dir: String::from("../example-file"),
+                ..Default::default()
             }),
             ..Default::default()
         };
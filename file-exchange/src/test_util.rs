@@ -1,11 +1,16 @@
+use alloy_primitives::{Address, U256};
+use ethers::signers::LocalWallet;
 use rand::{distributions::Alphanumeric, seq::IteratorRandom, Rng};
 use reqwest::StatusCode;
 use std::env;
 use std::io::Write;
+use std::str::FromStr;
 use std::time::Duration;
+use tap_core::{eip_712_signed_message::EIP712SignedMessage, tap_receipt::Receipt};
 use tempfile::NamedTempFile;
 
 use crate::config::init_tracing;
+use crate::download_client::signer::{receipt_domain, TapReceipt};
 use crate::manifest::{
     BlockRange, Bundle, BundleManifest, FileManifest, FileManifestMeta, FileMetaInfo,
 };
@@ -140,3 +145,27 @@ pub async fn server_ready(url: &str) -> Result<(), anyhow::Error> {
         tokio::time::sleep(Duration::from_secs(1)).await;
     }
 }
+
+// Helper function to sign a receipt with a hex private key under the domain of a chain and
+// receipts verifier
+pub async fn signed_receipt(
+    private_key: &str,
+    chain_id: u64,
+    verifier: &str,
+    allocation_id: &str,
+    timestamp_ns: u64,
+    nonce: u64,
+    value: u128,
+) -> TapReceipt {
+    let wallet = LocalWallet::from_str(private_key).unwrap();
+    let domain = receipt_domain(U256::from(chain_id), Address::from_str(verifier).unwrap());
+    let receipt = Receipt {
+        allocation_id: Address::from_str(allocation_id).unwrap(),
+        timestamp_ns,
+        nonce,
+        value,
+    };
+    EIP712SignedMessage::new(&domain, receipt, &wallet)
+        .await
+        .unwrap()
+}
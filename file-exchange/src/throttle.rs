@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often buckets of idle peers are dropped
+const PEER_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token bucket limiting a transfer rate in bytes per second, holding up to one second of
+/// tokens for bursts. A rate of 0 leaves transfers unlimited.
+#[derive(Debug)]
+pub struct TokenBucket {
+    state: StdMutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            state: StdMutex::new(BucketState {
+                rate,
+                tokens: rate as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.state.lock().unwrap().rate
+    }
+
+    /// Change the rate, taking effect for the next transfers
+    pub fn set_rate(&self, rate: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.rate = rate;
+        state.tokens = state.tokens.min(rate as f64);
+    }
+
+    /// Whether the bucket has refilled to capacity, so it limits transfers no differently than
+    /// a new bucket
+    fn is_full(&self, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        let refill = now.duration_since(state.updated).as_secs_f64() * state.rate as f64;
+        state.rate == 0 || state.tokens + refill >= state.rate as f64
+    }
+
+    /// Take tokens for a transfer of `bytes`, waiting until the bucket has refilled enough.
+    /// Transfers larger than the bucket go into debt that later transfers wait off.
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            if state.rate == 0 {
+                return;
+            }
+            let now = Instant::now();
+            let refill = now.duration_since(state.updated).as_secs_f64() * state.rate as f64;
+            state.tokens = (state.tokens + refill).min(state.rate as f64);
+            state.updated = now;
+            state.tokens -= bytes as f64;
+            if state.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.tokens / state.rate as f64)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Bandwidth limits of transfers, in total and per peer such as a provider or a consumer
+#[derive(Debug)]
+pub struct Throttle {
+    global: TokenBucket,
+    peer_rate: AtomicU64,
+    peers: StdMutex<PeerBuckets>,
+}
+
+/// Buckets of the peers seen since the last sweep of idle peers
+#[derive(Debug)]
+struct PeerBuckets {
+    buckets: HashMap<String, Arc<TokenBucket>>,
+    swept: Instant,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle::new(0, 0)
+    }
+}
+
+impl Throttle {
+    pub fn new(global_rate: u64, peer_rate: u64) -> Self {
+        Throttle {
+            global: TokenBucket::new(global_rate),
+            peer_rate: AtomicU64::new(peer_rate),
+            peers: StdMutex::new(PeerBuckets {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// Current limits in bytes per second, in total and per peer
+    pub fn limits(&self) -> (u64, u64) {
+        (self.global.rate(), self.peer_rate.load(Ordering::Relaxed))
+    }
+
+    /// Change the limits in bytes per second for ongoing and new transfers
+    pub fn set_limits(&self, global_rate: u64, peer_rate: u64) {
+        self.global.set_rate(global_rate);
+        self.peer_rate.store(peer_rate, Ordering::Relaxed);
+        for bucket in self.peers.lock().unwrap().buckets.values() {
+            bucket.set_rate(peer_rate);
+        }
+        tracing::info!(global_rate, peer_rate, "Set bandwidth limits");
+    }
+
+    /// Wait until `bytes` may be transferred with a peer. Buckets of peers that have refilled
+    /// are dropped from time to time, so peers seen once do not pile up.
+    pub async fn acquire(&self, peer: &str, bytes: u64) {
+        let bucket = {
+            let mut peers = self.peers.lock().unwrap();
+            let now = Instant::now();
+            if now.duration_since(peers.swept) >= PEER_SWEEP_INTERVAL {
+                // Buckets held by ongoing transfers or still in debt are kept
+                peers
+                    .buckets
+                    .retain(|_, bucket| Arc::strong_count(bucket) > 1 || !bucket.is_full(now));
+                peers.swept = now;
+            }
+            peers
+                .buckets
+                .entry(peer.to_string())
+                .or_insert_with(|| {
+                    Arc::new(TokenBucket::new(self.peer_rate.load(Ordering::Relaxed)))
+                })
+                .clone()
+        };
+        bucket.acquire(bytes).await;
+        self.global.acquire(bytes).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket_paces_transfers() {
+        let bucket = TokenBucket::new(1000);
+        let started = Instant::now();
+        // The first second of tokens is available as a burst
+        bucket.acquire(1000).await;
+        assert!(started.elapsed() < Duration::from_millis(10));
+        bucket.acquire(2000).await;
+        assert!(started.elapsed() >= Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unlimited_and_runtime_change() {
+        let throttle = Throttle::default();
+        let started = Instant::now();
+        throttle.acquire("a", 1 << 30).await;
+        assert!(started.elapsed() < Duration::from_millis(10));
+
+        throttle.set_limits(0, 100);
+        assert_eq!(throttle.limits(), (0, 100));
+        throttle.acquire("a", 100).await;
+        throttle.acquire("a", 100).await;
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        // Peers have separate buckets
+        let other = Instant::now();
+        throttle.acquire("b", 100).await;
+        assert!(other.elapsed() < Duration::from_millis(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_peers_dropped() {
+        let throttle = Throttle::new(0, 100);
+        throttle.acquire("a", 100).await;
+        tokio::time::advance(PEER_SWEEP_INTERVAL - Duration::from_millis(500)).await;
+        // Leaves the bucket of b in debt past the sweep
+        throttle.acquire("b", 200).await;
+
+        throttle.acquire("c", 1).await;
+        let peers = throttle.peers.lock().unwrap();
+        assert!(!peers.buckets.contains_key("a"));
+        assert!(peers.buckets.contains_key("b"));
+        assert_eq!(peers.buckets.len(), 2);
+        drop(peers);
+
+        // The debt of b still holds back its next transfer
+        let started = Instant::now();
+        throttle.acquire("b", 100).await;
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+}
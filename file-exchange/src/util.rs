@@ -3,6 +3,7 @@ use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer
 use ethers_core::k256::ecdsa::SigningKey;
 use ethers_core::types::H160;
 use hdwallet::{ChainPath, DefaultKeyChain, KeyChain};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     collections::{HashMap, HashSet},
     fmt,
@@ -75,7 +76,7 @@ pub fn derive_key_pair(
 const ONE_18: u128 = 1_000_000_000_000_000_000;
 
 /// GRT with 18 fractional digits
-#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct GRT(pub UDecimal18);
 
 /// Represents a positive decimal value with 18 fractional digits precision. Using U256 as storage.
@@ -174,6 +175,21 @@ impl fmt::Display for UDecimal18 {
     }
 }
 
+/// Decimals are written as strings, exact at any magnitude
+impl Serialize for UDecimal18 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for UDecimal18 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
 impl fmt::Debug for UDecimal18 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{self}")
@@ -201,6 +217,10 @@ impl UDecimal18 {
     pub fn saturating_add(self, rhs: Self) -> Self {
         Self(self.0.saturating_add(rhs.0))
     }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Self(self.0.saturating_sub(rhs.0))
+    }
 }
 
 impl std::ops::Add for UDecimal18 {
@@ -230,12 +250,66 @@ impl std::iter::Sum for UDecimal18 {
     }
 }
 
-// Takes a HashMap<String, Vec<u64>> and writes it to a specified file in JSON format.
-pub fn store_map_as_json(
-    map: &HashMap<String, HashSet<u64>>,
-    file_path: &str,
-) -> Result<(), Error> {
-    let serialized = serde_json::to_string(map).map_err(Error::JsonError)?;
+impl GRT {
+    /// Amount of GRT given as a float, taken at the shortest decimal that reads back as it,
+    /// such as a price quoted by a provider or configured, rather than at its binary
+    /// approximation
+    pub fn from_decimal(value: f64) -> Result<GRT, Error> {
+        value
+            .to_string()
+            .parse::<UDecimal18>()
+            .map(GRT)
+            .map_err(|_| Error::PricingError(format!("Invalid GRT amount: {}", value)))
+    }
+
+    /// Price of a number of bytes at a price per byte, exact to 18 fractional digits
+    pub fn for_bytes(price_per_byte: f64, bytes: u64) -> Result<GRT, Error> {
+        let price = GRT::from_decimal(price_per_byte)?;
+        let value =
+            price
+                .0
+                .raw_u256()
+                .checked_mul(U256::from(bytes))
+                .ok_or(Error::PricingError(format!(
+                    "Price of {} bytes at {} out of range",
+                    bytes, price_per_byte
+                )))?;
+        Ok(GRT(UDecimal18::from_raw_u256(value)))
+    }
+
+    /// Value in the smallest unit of GRT, as carried by receipts
+    pub fn wei(&self) -> Option<u128> {
+        u128::try_from(*self.0.raw_u256()).ok()
+    }
+}
+
+/// Progress of a download kept to resume from: the chunks left per file manifest, and the
+/// value of receipts issued so far
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProgressCache {
+    pub chunks: HashMap<String, HashSet<u64>>,
+    #[serde(default)]
+    pub spend: SpendTotals,
+}
+
+/// Value of receipts issued, in total and per provider service endpoint
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpendTotals {
+    pub total: GRT,
+    pub providers: HashMap<String, GRT>,
+}
+
+/// Progress files written before spend tracking only hold the chunks left
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProgressFormat {
+    Cache(ProgressCache),
+    Chunks(HashMap<String, HashSet<u64>>),
+}
+
+// Writes the progress of a download to a specified file in JSON format.
+pub fn store_progress(progress: &ProgressCache, file_path: &str) -> Result<(), Error> {
+    let serialized = serde_json::to_string(progress).map_err(Error::JsonError)?;
 
     let mut file = File::create(file_path).map_err(Error::FileIOError)?;
 
@@ -245,27 +319,25 @@ pub fn store_map_as_json(
     Ok(())
 }
 
-// Reads a JSON file and converts it into a HashMap<String, HashSet<u64>>.
-pub fn read_json_to_map(file_path: &str) -> Result<HashMap<String, HashSet<u64>>, Error> {
+// Reads the progress of a download from a JSON file; a missing file is an empty progress.
+pub fn read_progress(file_path: &str) -> Result<ProgressCache, Error> {
     match File::open(file_path) {
         Ok(mut file) => {
             let mut contents = String::new();
             file.read_to_string(&mut contents)
                 .map_err(Error::FileIOError)?;
 
-            let temp_map: HashMap<String, Vec<u64>> =
-                serde_json::from_str(&contents).map_err(Error::JsonError)?;
-
-            let map: HashMap<String, HashSet<u64>> = temp_map
-                .into_iter()
-                .map(|(key, vec)| (key, vec.into_iter().collect::<HashSet<u64>>()))
-                .collect();
-
-            Ok(map)
+            match serde_json::from_str(&contents).map_err(Error::JsonError)? {
+                ProgressFormat::Cache(progress) => Ok(progress),
+                ProgressFormat::Chunks(chunks) => Ok(ProgressCache {
+                    chunks,
+                    spend: SpendTotals::default(),
+                }),
+            }
         }
         Err(e) => {
             tracing::info!("Failed to open file at '{}'. Error: {}.", file_path, e);
-            Ok(HashMap::new())
+            Ok(ProgressCache::default())
         }
     }
 }
@@ -364,4 +436,61 @@ mod test {
             assert!(error < 0.005);
         }
     }
+
+    #[test]
+    fn grt_for_bytes() {
+        let tests = [
+            (1.0, 10, 10 * ONE_18),
+            (0.0042, 1000, 4_200_000_000_000_000_000),
+            (0.1, 3, 300_000_000_000_000_000),
+            (1e-18, 7, 7),
+            (0.0, 1000, 0),
+        ];
+        for (price, bytes, wei) in tests {
+            let fee = GRT::for_bytes(price, bytes).unwrap();
+            assert_eq!(fee.wei(), Some(wei));
+        }
+        assert!(GRT::for_bytes(-1.0, 10).is_err());
+        assert!(GRT::for_bytes(f64::NAN, 10).is_err());
+    }
+
+    #[test]
+    fn progress_cache_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("progress.json");
+        let path = path.to_str().unwrap();
+
+        // Progress files of earlier versions only hold the chunks left
+        std::fs::write(path, r#"{"QmFile": [1, 2]}"#).unwrap();
+        let progress = read_progress(path).unwrap();
+        assert_eq!(progress.chunks["QmFile"], HashSet::from([1, 2]));
+        assert_eq!(progress.spend, SpendTotals::default());
+
+        let spend = SpendTotals {
+            total: GRT::from_decimal(0.0042).unwrap(),
+            providers: HashMap::from([(
+                "http://indexer-a.xyz".to_string(),
+                GRT::from_decimal(0.0042).unwrap(),
+            )]),
+        };
+        store_progress(
+            &ProgressCache {
+                chunks: progress.chunks,
+                spend: spend.clone(),
+            },
+            path,
+        )
+        .unwrap();
+        assert!(std::fs::read_to_string(path)
+            .unwrap()
+            .contains(r#""total":"0.0042""#));
+        let progress = read_progress(path).unwrap();
+        assert_eq!(progress.chunks["QmFile"], HashSet::from([1, 2]));
+        assert_eq!(progress.spend, spend);
+
+        assert!(read_progress(dir.path().join("missing").to_str().unwrap())
+            .unwrap()
+            .chunks
+            .is_empty());
+    }
 }
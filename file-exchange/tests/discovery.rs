@@ -182,6 +182,23 @@ mod tests {
             "QmSuyvzDpuDBoka2rCimRXPmX2icL7Vu6RUxoFWFQD7YBb"
         )));
 
+        // 3.7 rank bundles overlapping bundle_0, the exact bundle first
+        let ranking = finder
+            .bundle_compatibilities(
+                &bundle_hash_0,
+                &[server_0.to_string(), server_1.to_string()],
+            )
+            .await
+            .unwrap();
+        assert!(ranking.len() == 4);
+        assert!(ranking[0].bundle == bundle_hash_0);
+        assert!(ranking[0].file_percentage == 100.0);
+        assert!(ranking[0].byte_percentage == 100.0);
+        assert!(ranking[0].providers[0].service_endpoint == server_0);
+        let alternative = ranking.iter().find(|c| c.bundle == bundle_hash_2).unwrap();
+        assert!(alternative.matched_files.len() == 2);
+        assert!(alternative.providers[0].service_endpoint == server_1);
+
         // 4. Cleanup
         let _ = server_process_0.kill();
         let _ = server_process_1.kill();
@@ -40,6 +40,7 @@ mod tests {
         let downloader_args = DownloaderArgs {
             storage_method: file_exchange::config::StorageMethod::LocalFiles(LocalDirectory {
                 main_dir: main_dir.to_str().unwrap().to_string(),
+                ..Default::default()
             }),
             ipfs_hash: target_bundle,
             indexer_endpoints: [
@@ -52,6 +53,9 @@ mod tests {
             free_query_auth_token: Some("Bearer free-token".to_string()),
             provider: None,
             provider_concurrency: 2,
+            chunk_concurrency: 16,
+            provider_chunk_concurrency: 8,
+            chunks_per_request: 4,
             ..Default::default()
         };
 
@@ -0,0 +1,13 @@
+-- Verification records of served files, keyed by bundle and file manifest.
+-- A file whose object identity (e_tag, last_modified, size) is unchanged since
+-- the last successful verification is trusted without re-hashing on start-up.
+CREATE TABLE IF NOT EXISTS file_verifications (
+    bundle_hash VARCHAR NOT NULL,
+    file_hash VARCHAR NOT NULL,
+    location VARCHAR NOT NULL,
+    e_tag VARCHAR,
+    last_modified_ms BIGINT NOT NULL,
+    size BIGINT NOT NULL,
+    verified_at TIMESTAMP WITH TIME ZONE DEFAULT NOW() NOT NULL,
+    PRIMARY KEY (bundle_hash, file_hash)
+);
@@ -0,0 +1,12 @@
+-- Results of background integrity scrubbing of served files.
+-- Used for monitoring; a failed scrub removes the bundle from service.
+CREATE TABLE IF NOT EXISTS file_scrub_results (
+    id BIGSERIAL PRIMARY KEY,
+    bundle_hash VARCHAR NOT NULL,
+    file_hash VARCHAR NOT NULL,
+    passed BOOLEAN NOT NULL,
+    reason TEXT,
+    checked_at TIMESTAMP WITH TIME ZONE DEFAULT NOW() NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS file_scrub_results_bundle_hash_idx ON file_scrub_results (bundle_hash);
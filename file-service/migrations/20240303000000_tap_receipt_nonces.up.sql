@@ -0,0 +1,3 @@
+-- A receipt is redeemed once: a signer may not reuse a nonce on an allocation.
+-- Inserting accepted receipts against this index rejects replays atomically.
+CREATE UNIQUE INDEX IF NOT EXISTS scalar_tap_receipts_nonce_idx ON scalar_tap_receipts (signer_address, allocation_id, nonce);
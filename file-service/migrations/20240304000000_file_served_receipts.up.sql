@@ -0,0 +1,9 @@
+-- Receipts that paid for a file request. Receipts also arrive in the scalar-receipt header,
+-- which may already be stored in scalar_tap_receipts before the request is served, so replays
+-- are told apart by claiming the nonce here once per served request.
+CREATE TABLE IF NOT EXISTS file_served_receipts (
+    signer_address CHAR(40) NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    nonce NUMERIC(20) NOT NULL,
+    PRIMARY KEY (signer_address, allocation_id, nonce)
+);
@@ -0,0 +1,2 @@
+DELETE FROM file_scrub_results WHERE passed IS NULL;
+ALTER TABLE file_scrub_results ALTER COLUMN passed SET NOT NULL;
@@ -0,0 +1,3 @@
+-- Scrubs of files that could not be read, such as on transient storage errors, are
+-- inconclusive and recorded without an outcome.
+ALTER TABLE file_scrub_results ALTER COLUMN passed DROP NOT NULL;
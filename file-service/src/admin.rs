@@ -1,32 +1,40 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use async_graphql::{Context, EmptySubscription, MergedObject, Object, Schema};
+use async_graphql::{Context, EmptySubscription, MergedObject, Object, Schema, SimpleObject};
 use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
 use axum::{extract::State, routing::get, Router, Server};
 use http::HeaderMap;
+use sqlx::PgPool;
 use tokio::sync::Mutex;
 
+use crate::database;
 use crate::file_server::{
     cost::{GraphQlCostModel, PriceQuery},
     status::{GraphQlBundle, StatusQuery},
+    storage::BundleStores,
     util::graphql_playground,
-    FileServiceError, ServerContext,
+    validate_bundle, FileServiceError, ServerContext,
 };
 use file_exchange::{
     errors::{Error, ServerError},
     manifest::{
         ipfs::IpfsClient, manifest_fetcher::read_bundle, validate_bundle_and_location, LocalBundle,
     },
+    throttle::Throttle,
 };
 
 #[derive(Clone)]
 pub struct AdminState {
     pub client: IpfsClient,
     pub bundles: Arc<Mutex<HashMap<String, LocalBundle>>>,
+    pub quarantined_bundles: Arc<Mutex<HashMap<String, LocalBundle>>>,
     pub prices: Arc<Mutex<HashMap<String, f64>>>,
     pub admin_auth_token: Option<String>,
     pub admin_schema: AdminSchema,
+    pub stores: BundleStores,
+    pub database: PgPool,
+    pub throttle: Arc<Throttle>,
 }
 
 #[derive(Clone)]
@@ -44,14 +52,14 @@ impl AdminContext {
 pub struct MergedQuery(StatusQuery, PriceQuery);
 
 #[derive(MergedObject, Default)]
-pub struct MergedMutation(StatusMutation, PriceMutation);
+pub struct MergedMutation(StatusMutation, PriceMutation, BandwidthMutation);
 
 pub type AdminSchema = Schema<MergedQuery, MergedMutation, EmptySubscription>;
 
 pub async fn build_schema() -> AdminSchema {
     Schema::build(
         MergedQuery(StatusQuery, PriceQuery),
-        MergedMutation(StatusMutation, PriceMutation),
+        MergedMutation(StatusMutation, PriceMutation, BandwidthMutation),
         EmptySubscription,
     )
     .finish()
@@ -83,9 +91,13 @@ pub fn serve_admin(context: ServerContext) {
             AdminState {
                 client: context.state.client.clone(),
                 bundles: context.state.bundles.clone(),
+                quarantined_bundles: context.state.quarantined_bundles.clone(),
                 prices: context.state.prices.clone(),
                 admin_auth_token: context.state.admin_auth_token.clone(),
                 admin_schema: build_schema().await,
+                stores: context.state.stores.clone(),
+                database: context.state.database.clone(),
+                throttle: context.state.throttle.clone(),
             }
             .into(),
         );
@@ -137,28 +149,38 @@ impl StatusMutation {
                     .as_ref()
             )));
         }
-        let (hash, loc) = match validate_bundle_and_location(&deployment, &location) {
+        let (hash, location) = match validate_bundle_and_location(&deployment, &location) {
             Ok(s) => s,
             Err(e) => return Err(anyhow::anyhow!("Invalid input: {}", e.to_string())),
         };
+        let stores = &ctx.data_unchecked::<AdminContext>().state.stores;
+        let (store, loc) = match stores.resolve(&location).await {
+            Ok(s) => s,
+            Err(e) => return Err(anyhow::anyhow!("Invalid location: {}", e.to_string())),
+        };
         let bundle =
             match read_bundle(&ctx.data_unchecked::<AdminContext>().state.client, &hash).await {
                 Ok(s) => s,
                 Err(e) => return Err(anyhow::anyhow!(e.to_string(),)),
             };
+        let local = LocalBundle {
+            bundle: bundle.clone(),
+            local_path: loc,
+        };
+
+        // Only serve bundles whose files match their manifests
+        let database = &ctx.data_unchecked::<AdminContext>().state.database;
+        if let Err(e) = validate_bundle(&store, database, &local).await {
+            return Err(anyhow::anyhow!("Invalid bundle: {}", e.to_string()));
+        }
 
+        stores.assign(&bundle.ipfs_hash, store).await;
         ctx.data_unchecked::<AdminContext>()
             .state
             .bundles
             .lock()
             .await
-            .insert(
-                bundle.ipfs_hash.clone(),
-                LocalBundle {
-                    bundle: bundle.clone(),
-                    local_path: loc,
-                },
-            );
+            .insert(bundle.ipfs_hash.clone(), local);
 
         Ok(GraphQlBundle::from(bundle))
     }
@@ -181,30 +203,44 @@ impl StatusMutation {
         }
         let client = ctx.data_unchecked::<AdminContext>().state.client.clone();
         let bundle_ref = ctx.data_unchecked::<AdminContext>().state.bundles.clone();
+        let stores = ctx.data_unchecked::<AdminContext>().state.stores.clone();
+        let database = ctx.data_unchecked::<AdminContext>().state.database.clone();
         let bundles = deployments
             .iter()
             .zip(locations)
             .map(|(deployment, location)| {
                 let client = client.clone();
                 let bundle_ref = bundle_ref.clone();
+                let stores = stores.clone();
+                let database = database.clone();
 
                 async move {
                     tracing::debug!(deployment, location, "Adding bundle");
 
-                    let (hash, loc) = validate_bundle_and_location(deployment, &location)
+                    let (hash, location) = validate_bundle_and_location(deployment, &location)
                         .map_err(|e| anyhow::anyhow!("Invalid input: {}", e))?;
+                    let (store, loc) = stores
+                        .resolve(&location)
+                        .await
+                        .map_err(|e| anyhow::anyhow!("Invalid location: {}", e))?;
 
                     let bundle = read_bundle(&client.clone(), &hash)
                         .await
                         .map_err(|e| anyhow::anyhow!("{}", e))?;
+                    let local = LocalBundle {
+                        bundle: bundle.clone(),
+                        local_path: loc,
+                    };
+                    validate_bundle(&store, &database, &local)
+                        .await
+                        .map_err(|e| anyhow::anyhow!("Invalid bundle: {}", e))?;
 
-                    bundle_ref.clone().lock().await.insert(
-                        bundle.ipfs_hash.clone(),
-                        LocalBundle {
-                            bundle: bundle.clone(),
-                            local_path: loc,
-                        },
-                    );
+                    stores.assign(&bundle.ipfs_hash, store).await;
+                    bundle_ref
+                        .clone()
+                        .lock()
+                        .await
+                        .insert(bundle.ipfs_hash.clone(), local);
 
                     Ok::<_, anyhow::Error>(GraphQlBundle::from(bundle))
                 }
@@ -233,14 +269,16 @@ impl StatusMutation {
             return Err(anyhow::anyhow!("Failed to authenticate"));
         }
 
-        let bundle = ctx
-            .data_unchecked::<AdminContext>()
-            .state
+        let state = &ctx.data_unchecked::<AdminContext>().state;
+        let bundle = state
             .bundles
             .lock()
             .await
             .remove(&deployment)
             .map(|b| GraphQlBundle::from(b.bundle));
+        if bundle.is_some() {
+            state.stores.unassign(&deployment).await;
+        }
 
         Ok(bundle)
     }
@@ -263,8 +301,8 @@ impl StatusMutation {
         let bundles = deployments
             .iter()
             .map(|deployment| async move {
-                ctx.data_unchecked::<AdminContext>()
-                    .state
+                let state = &ctx.data_unchecked::<AdminContext>().state;
+                let bundle = state
                     .bundles
                     .lock()
                     .await
@@ -273,7 +311,9 @@ impl StatusMutation {
                     .ok_or(anyhow::anyhow!(format!(
                         "Deployment not found: {}",
                         deployment
-                    )))
+                    )))?;
+                state.stores.unassign(deployment).await;
+                Ok::<_, anyhow::Error>(bundle)
             })
             .collect::<Vec<_>>();
 
@@ -282,6 +322,71 @@ impl StatusMutation {
 
         removed_bundles
     }
+
+    // Discard verification records of a served bundle and re-hash all of its files
+    async fn force_revalidate(
+        &self,
+        ctx: &Context<'_>,
+        deployment: String,
+    ) -> Result<GraphQlBundle, anyhow::Error> {
+        if ctx.data_opt::<String>()
+            != ctx
+                .data_unchecked::<AdminContext>()
+                .state
+                .admin_auth_token
+                .as_ref()
+        {
+            return Err(anyhow::anyhow!("Failed to authenticate"));
+        }
+        let state = &ctx.data_unchecked::<AdminContext>().state;
+
+        // Bundles taken out of service by the scrubber can be revalidated as well
+        let served = state.bundles.lock().await.get(&deployment).cloned();
+        let local = match served {
+            Some(local) => local,
+            None => state
+                .quarantined_bundles
+                .lock()
+                .await
+                .get(&deployment)
+                .cloned()
+                .ok_or(anyhow::anyhow!(format!(
+                    "Deployment not found: {}",
+                    deployment
+                )))?,
+        };
+
+        let cleared = database::clear_verifications(&state.database, &deployment).await?;
+        tracing::info!(deployment, cleared, "Cleared verification records");
+
+        let store = state.stores.bundle_store(&deployment).await;
+        if let Err(e) = validate_bundle(&store, &state.database, &local).await {
+            tracing::error!(
+                deployment,
+                error = e.to_string(),
+                "Revalidation failed, stop serving bundle"
+            );
+            if let Some(removed) = state.bundles.lock().await.remove(&deployment) {
+                state
+                    .quarantined_bundles
+                    .lock()
+                    .await
+                    .insert(deployment.clone(), removed);
+            }
+            return Err(anyhow::anyhow!("Revalidation failed: {}", e));
+        }
+
+        // Put a previously quarantined bundle back into service
+        if let Some(restored) = state.quarantined_bundles.lock().await.remove(&deployment) {
+            state
+                .bundles
+                .lock()
+                .await
+                .insert(deployment.clone(), restored);
+        }
+
+        Ok(GraphQlBundle::from(local.bundle))
+    }
 }
 
 #[derive(Default)]
@@ -442,3 +547,44 @@ impl PriceMutation {
         removed_prices
     }
 }
+
+/// Bandwidth limits in bytes per second, 0 for no limit
+#[derive(Clone, Debug, SimpleObject)]
+pub struct GraphQlBandwidthLimits {
+    pub max_serve_rate: u64,
+    pub max_consumer_serve_rate: u64,
+}
+
+#[derive(Default)]
+pub struct BandwidthMutation;
+
+#[Object]
+impl BandwidthMutation {
+    // Set serving bandwidth limits, in total and per consumer, for ongoing and new transfers
+    async fn set_bandwidth_limits(
+        &self,
+        ctx: &Context<'_>,
+        max_serve_rate: u64,
+        max_consumer_serve_rate: u64,
+    ) -> Result<GraphQlBandwidthLimits, anyhow::Error> {
+        if ctx.data_opt::<String>()
+            != ctx
+                .data_unchecked::<AdminContext>()
+                .state
+                .admin_auth_token
+                .as_ref()
+        {
+            return Err(anyhow::anyhow!("Failed to authenticate"));
+        }
+
+        ctx.data_unchecked::<AdminContext>()
+            .state
+            .throttle
+            .set_limits(max_serve_rate, max_consumer_serve_rate);
+
+        Ok(GraphQlBandwidthLimits {
+            max_serve_rate,
+            max_consumer_serve_rate,
+        })
+    }
+}
@@ -3,10 +3,10 @@ use figment::{
     providers::{Format, Toml},
     Figment,
 };
-use file_exchange::config::StorageMethod;
+use file_exchange::config::{StorageMethod, StoreOptions};
 use indexer_common::indexer_service::http::IndexerServiceConfig;
 use serde::{Deserialize, Serialize};
-use std::{fmt, net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, fmt, net::SocketAddr, path::PathBuf};
 
 #[derive(Parser)]
 pub struct Cli {
@@ -35,7 +35,7 @@ pub struct ServerArgs {
         value_name = "initial-bundles",
         env = "INITIAL_BUNDLES",
         value_delimiter = ',',
-        help = "Comma separated list of IPFS hashes and shared prefix of files in the bundles (empty if just in main_directory) to serve upon start-up; the list can be managed through the /admin API without service restart.\nformat: [ipfs_hash:prefix]"
+        help = "Comma separated list of IPFS hashes and locations of files in the bundles to serve upon start-up; the list can be managed through the /admin API without service restart. A location is a shared prefix in the main storage (empty if just in main_directory), a local directory (file:///path), or an S3 bucket with a named storage profile (s3://bucket/prefix?profile=name).\nformat: [ipfs_hash:location]"
     )]
     pub initial_bundles: Vec<String>,
     #[clap(
@@ -89,6 +89,109 @@ pub struct ServerArgs {
         help = "Default price per byte in GRT"
     )]
     pub default_price_per_byte: f64,
+    #[arg(
+        long,
+        value_name = "receipt-max-age",
+        default_value = "60",
+        env = "RECEIPT_MAX_AGE",
+        help = "Seconds a receipt timestamp may differ from the current time to be accepted"
+    )]
+    #[serde(default = "default_receipt_max_age")]
+    pub receipt_max_age: u64,
+    #[arg(
+        long,
+        value_name = "scrub-interval",
+        default_value = "86400",
+        env = "SCRUB_INTERVAL",
+        help = "Seconds between background integrity scrubs of all served files (0 disables scrubbing)"
+    )]
+    #[serde(default = "default_scrub_interval")]
+    pub scrub_interval: u64,
+    #[arg(
+        long,
+        value_name = "scrub-bandwidth",
+        default_value = "10485760",
+        env = "SCRUB_BANDWIDTH",
+        help = "Maximum bytes per second read from storage by the background scrubber"
+    )]
+    #[serde(default = "default_scrub_bandwidth")]
+    pub scrub_bandwidth: u64,
+    #[arg(
+        long,
+        value_name = "chunk-cache-memory-bytes",
+        default_value = "268435456",
+        env = "CHUNK_CACHE_MEMORY_BYTES",
+        help = "Maximum bytes of hot chunks cached in memory for object storage (0 disables the memory tier)"
+    )]
+    #[serde(default = "default_chunk_cache_memory_bytes")]
+    pub chunk_cache_memory_bytes: u64,
+    #[arg(
+        long,
+        value_name = "chunk-cache-dir",
+        env = "CHUNK_CACHE_DIR",
+        help = "Local directory for caching hot chunks of object storage on disk"
+    )]
+    #[serde(default)]
+    pub chunk_cache_dir: Option<String>,
+    #[arg(
+        long,
+        value_name = "chunk-cache-disk-bytes",
+        default_value = "0",
+        env = "CHUNK_CACHE_DISK_BYTES",
+        help = "Maximum bytes of hot chunks cached in the chunk cache directory (0 disables the disk tier)"
+    )]
+    #[serde(default)]
+    pub chunk_cache_disk_bytes: u64,
+    #[arg(
+        long,
+        value_name = "max-serve-rate",
+        default_value = "0",
+        env = "MAX_SERVE_RATE",
+        help = "Maximum bytes per second served across all consumers (0 for no limit); adjustable at runtime with the setBandwidthLimits admin mutation"
+    )]
+    #[serde(default)]
+    pub max_serve_rate: u64,
+    #[arg(
+        long,
+        value_name = "max-consumer-serve-rate",
+        default_value = "0",
+        env = "MAX_CONSUMER_SERVE_RATE",
+        help = "Maximum bytes per second served to a single consumer (0 for no limit); adjustable at runtime with the setBandwidthLimits admin mutation"
+    )]
+    #[serde(default)]
+    pub max_consumer_serve_rate: u64,
+    // Named credentials for bundles hosted in object storage other than the main storage
+    #[arg(skip)]
+    #[serde(default)]
+    pub storage_profiles: HashMap<String, StorageProfile>,
+}
+
+/// Credentials and endpoint of an object storage backend, referenced by name in bundle
+/// locations such as "s3://bucket/prefix?profile=name"
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StorageProfile {
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_key: String,
+    pub endpoint: String,
+    #[serde(flatten)]
+    pub store_options: StoreOptions,
+}
+
+fn default_receipt_max_age() -> u64 {
+    60
+}
+
+fn default_scrub_interval() -> u64 {
+    86400
+}
+
+fn default_scrub_bandwidth() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_chunk_cache_memory_bytes() -> u64 {
+    256 * 1024 * 1024
 }
 
 #[derive(clap::ValueEnum, Clone, Debug, Serialize, Deserialize, Default)]
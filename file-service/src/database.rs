@@ -1,6 +1,14 @@
 use std::time::Duration;
 
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use object_store::ObjectMeta;
+use sqlx::{
+    postgres::{PgPoolOptions, PgRow},
+    types::BigDecimal,
+    PgPool, Row,
+};
+use std::str::FromStr;
+
+use file_exchange::download_client::signer::TapReceipt;
 
 use tracing::debug;
 
@@ -14,3 +22,334 @@ pub async fn connect(url: &str) -> PgPool {
         .await
         .expect("Should be able to connect to the database")
 }
+
+/// Check for a verification record matching the current object identity of a served file
+pub async fn is_verified(
+    pool: &PgPool,
+    bundle_hash: &str,
+    file_hash: &str,
+    meta: &ObjectMeta,
+) -> Result<bool, sqlx::Error> {
+    let record = sqlx::query(
+        r#"SELECT location, e_tag, last_modified_ms, size
+        FROM file_verifications
+        WHERE bundle_hash = $1 AND file_hash = $2"#,
+    )
+    .bind(bundle_hash)
+    .bind(file_hash)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(record.is_some_and(|row| {
+        row.get::<String, _>("location") == meta.location.to_string()
+            && row.get::<Option<String>, _>("e_tag") == meta.e_tag
+            && row.get::<i64, _>("last_modified_ms") == meta.last_modified.timestamp_millis()
+            && row.get::<i64, _>("size") == meta.size as i64
+    }))
+}
+
+/// Record a successful verification of a served file at its current object identity
+pub async fn record_verification(
+    pool: &PgPool,
+    bundle_hash: &str,
+    file_hash: &str,
+    meta: &ObjectMeta,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"INSERT INTO file_verifications
+            (bundle_hash, file_hash, location, e_tag, last_modified_ms, size, verified_at)
+        VALUES ($1, $2, $3, $4, $5, $6, NOW())
+        ON CONFLICT (bundle_hash, file_hash) DO UPDATE SET
+            location = EXCLUDED.location,
+            e_tag = EXCLUDED.e_tag,
+            last_modified_ms = EXCLUDED.last_modified_ms,
+            size = EXCLUDED.size,
+            verified_at = EXCLUDED.verified_at"#,
+    )
+    .bind(bundle_hash)
+    .bind(file_hash)
+    .bind(meta.location.to_string())
+    .bind(meta.e_tag.clone())
+    .bind(meta.last_modified.timestamp_millis())
+    .bind(meta.size as i64)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Remove verification records of a bundle so that its files are re-hashed on next validation
+pub async fn clear_verifications(pool: &PgPool, bundle_hash: &str) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(r#"DELETE FROM file_verifications WHERE bundle_hash = $1"#)
+        .bind(bundle_hash)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Record the outcome of scrubbing a served file; an inconclusive scrub, where the file could
+/// not be read, has no outcome
+pub async fn record_scrub_result(
+    pool: &PgPool,
+    bundle_hash: &str,
+    file_hash: &str,
+    passed: Option<bool>,
+    reason: Option<String>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"INSERT INTO file_scrub_results (bundle_hash, file_hash, passed, reason)
+        VALUES ($1, $2, $3, $4)"#,
+    )
+    .bind(bundle_hash)
+    .bind(file_hash)
+    .bind(passed)
+    .bind(reason)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Store an accepted receipt of a signer, given as hex without prefix, for aggregation into
+/// RAVs. The nonce is claimed for the served request first, so a replay is reported as
+/// `false`. The receipt itself may already be stored from the `scalar-receipt` header, which
+/// is accepted as long as it carries the same signature.
+pub async fn store_receipt(
+    pool: &PgPool,
+    signer: &str,
+    receipt: &TapReceipt,
+) -> Result<bool, sqlx::Error> {
+    let message = &receipt.message;
+    let allocation = hex::encode(message.allocation_id);
+    let signature = receipt.signature.to_vec();
+    let mut tx = pool.begin().await?;
+    let claimed = sqlx::query(
+        r#"INSERT INTO file_served_receipts (signer_address, allocation_id, nonce)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (signer_address, allocation_id, nonce) DO NOTHING"#,
+    )
+    .bind(signer)
+    .bind(&allocation)
+    .bind(numeric(message.nonce))
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        == 1;
+
+    let inserted = sqlx::query(
+        r#"INSERT INTO scalar_tap_receipts
+            (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (signer_address, allocation_id, nonce) DO NOTHING"#,
+    )
+    .bind(signer)
+    .bind(&signature)
+    .bind(&allocation)
+    .bind(numeric(message.timestamp_ns))
+    .bind(numeric(message.nonce))
+    .bind(numeric(message.value))
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        == 1;
+    let stored_signature = if inserted {
+        None
+    } else {
+        let stored = sqlx::query(
+            r#"SELECT signature FROM scalar_tap_receipts
+            WHERE signer_address = $1 AND allocation_id = $2 AND nonce = $3"#,
+        )
+        .bind(signer)
+        .bind(&allocation)
+        .bind(numeric(message.nonce))
+        .fetch_one(&mut *tx)
+        .await?;
+        Some(stored.try_get::<Vec<u8>, _>("signature")?)
+    };
+
+    // Dropping the transaction without committing releases the claim of a replay
+    if !redeemable(claimed, stored_signature.as_deref(), &signature) {
+        return Ok(false);
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Whether a receipt pays for a request: its nonce was not claimed by a request served before,
+/// and a receipt already stored under the nonce, such as from the request header, is the same
+/// receipt
+fn redeemable(claimed: bool, stored_signature: Option<&[u8]>, signature: &[u8]) -> bool {
+    claimed && stored_signature.map_or(true, |stored| stored == signature)
+}
+
+/// Receipt stored for aggregation, with amounts in wei
+#[derive(Debug)]
+struct StoredReceipt {
+    allocation_id: String,
+    nonce: u64,
+    timestamp_ns: u64,
+    value: u128,
+}
+
+/// RAV of a sender on an allocation, aggregating the receipts up to its timestamp
+#[derive(Debug)]
+struct StoredRav {
+    allocation_id: String,
+    timestamp_ns: u64,
+    value_aggregate: u128,
+    is_final: bool,
+}
+
+/// Value in wei a signer, given as hex without prefix, owes but has not had redeemed from
+/// escrow. See [`outstanding`].
+pub async fn outstanding_value(
+    pool: &PgPool,
+    signer: &str,
+    allocation_id: &str,
+    nonce: u64,
+) -> Result<u128, sqlx::Error> {
+    let receipts = sqlx::query(
+        r#"SELECT allocation_id, nonce::TEXT AS nonce, timestamp_ns::TEXT AS timestamp_ns,
+            value::TEXT AS value
+        FROM scalar_tap_receipts
+        WHERE signer_address = $1"#,
+    )
+    .bind(signer)
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| {
+        Ok(StoredReceipt {
+            allocation_id: row.try_get("allocation_id")?,
+            nonce: integer(row, "nonce")?,
+            timestamp_ns: integer(row, "timestamp_ns")?,
+            value: integer(row, "value")?,
+        })
+    })
+    .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    let ravs = sqlx::query(
+        r#"SELECT allocation_id, timestamp_ns::TEXT AS timestamp_ns,
+            value_aggregate::TEXT AS value_aggregate, final
+        FROM scalar_tap_ravs
+        WHERE sender_address = $1"#,
+    )
+    .bind(signer)
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| {
+        Ok(StoredRav {
+            allocation_id: row.try_get("allocation_id")?,
+            timestamp_ns: integer(row, "timestamp_ns")?,
+            value_aggregate: integer(row, "value_aggregate")?,
+            is_final: row.try_get("final")?,
+        })
+    })
+    .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    Ok(outstanding(&receipts, &ravs, allocation_id, nonce))
+}
+
+/// Value owed on receipts not yet aggregated into a RAV and on RAVs not yet final. The receipt
+/// of a nonce on an allocation is left out, so a receipt already stored from the header is not
+/// counted twice. Sums saturate, which only makes the escrow check stricter.
+fn outstanding(
+    receipts: &[StoredReceipt],
+    ravs: &[StoredRav],
+    allocation_id: &str,
+    nonce: u64,
+) -> u128 {
+    let aggregated_until = |allocation: &str| {
+        ravs.iter()
+            .find(|rav| rav.allocation_id == allocation)
+            .map(|rav| rav.timestamp_ns)
+    };
+    let unaggregated = receipts
+        .iter()
+        .filter(|r| !(r.allocation_id == allocation_id && r.nonce == nonce))
+        .filter(|r| aggregated_until(&r.allocation_id).map_or(true, |t| r.timestamp_ns > t))
+        .fold(0u128, |sum, r| sum.saturating_add(r.value));
+    ravs.iter()
+        .filter(|rav| !rav.is_final)
+        .fold(unaggregated, |sum, rav| {
+            sum.saturating_add(rav.value_aggregate)
+        })
+}
+
+/// Integer of a NUMERIC column selected as text, so no precision is lost
+fn integer<T>(row: &PgRow, column: &str) -> Result<T, sqlx::Error>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let text: String = row.try_get(column)?;
+    text.trim()
+        .parse()
+        .map_err(|e: T::Err| sqlx::Error::ColumnDecode {
+            index: column.to_string(),
+            source: Box::new(e),
+        })
+}
+
+/// Integer as a NUMERIC column value
+fn numeric(value: impl ToString) -> BigDecimal {
+    BigDecimal::from_str(&value.to_string()).expect("Integers are valid decimals")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALLOCATION: &str = "ab5d9d98b6dbd2e9b7c0e19fb3f2b9d7be0ff5d8";
+    const OTHER_ALLOCATION: &str = "1111111111111111111111111111111111111111";
+
+    fn receipt(allocation_id: &str, nonce: u64, timestamp_ns: u64, value: u128) -> StoredReceipt {
+        StoredReceipt {
+            allocation_id: allocation_id.to_string(),
+            nonce,
+            timestamp_ns,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_redeemable() {
+        let signature = [1u8; 65];
+        // A new receipt, or the same receipt stored from the header, pays once
+        assert!(redeemable(true, None, &signature));
+        assert!(redeemable(true, Some(&signature), &signature));
+        // A served nonce, or another receipt stored under the nonce, is a replay
+        assert!(!redeemable(false, None, &signature));
+        assert!(!redeemable(false, Some(&signature), &signature));
+        assert!(!redeemable(true, Some(&[2u8; 65]), &signature));
+    }
+
+    #[test]
+    fn test_outstanding() {
+        let receipts = [
+            // Aggregated into the RAV of the allocation
+            receipt(ALLOCATION, 1, 100, 10),
+            receipt(ALLOCATION, 2, 200, 20),
+            // Newer than the RAV
+            receipt(ALLOCATION, 3, 300, 30),
+            // The receipt being checked, already stored from the header
+            receipt(ALLOCATION, 4, 400, 1000),
+            // No RAV on the allocation yet
+            receipt(OTHER_ALLOCATION, 4, 100, u64::MAX as u128 + 1),
+        ];
+        let mut ravs = [StoredRav {
+            allocation_id: ALLOCATION.to_string(),
+            timestamp_ns: 200,
+            value_aggregate: 30,
+            is_final: false,
+        }];
+        let expected = 30 + (u64::MAX as u128 + 1) + 30;
+        assert_eq!(outstanding(&receipts, &ravs, ALLOCATION, 4), expected);
+        // A final RAV is no longer owed
+        ravs[0].is_final = true;
+        assert_eq!(outstanding(&receipts, &ravs, ALLOCATION, 4), expected - 30);
+        // Without the exclusion, the checked receipt would be counted
+        assert_eq!(
+            outstanding(&receipts, &ravs, ALLOCATION, 5),
+            expected - 30 + 1000
+        );
+        assert_eq!(outstanding(&[], &[], ALLOCATION, 4), 0);
+    }
+}
@@ -0,0 +1,288 @@
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use file_exchange::{errors::Error, manifest::file_hasher::verify_chunk};
+
+use crate::config::ServerArgs;
+use crate::metrics::{CHUNK_CACHE_BYTES, CHUNK_CACHE_HITS, CHUNK_CACHE_MISSES};
+
+/// Cached chunks are keyed by file manifest hash and chunk index
+pub type ChunkKey = (String, u64);
+
+/// Least-recently-used index bounded by the total size of its entries
+#[derive(Debug)]
+struct Lru<V> {
+    entries: HashMap<ChunkKey, (V, u64, usize)>,
+    order: BTreeMap<u64, ChunkKey>,
+    tick: u64,
+    size: usize,
+    capacity: usize,
+}
+
+impl<V: Clone> Lru<V> {
+    fn new(capacity: usize) -> Self {
+        Lru {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &ChunkKey) -> Option<V> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (value, last_used, _) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        *last_used = tick;
+        self.order.insert(tick, key.clone());
+        Some(value.clone())
+    }
+
+    /// Insert an entry and return the entries evicted to stay within capacity
+    fn insert(&mut self, key: ChunkKey, value: V, size: usize) -> Vec<ChunkKey> {
+        if size > self.capacity {
+            return vec![];
+        }
+        self.remove(&key);
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick, size));
+        self.size += size;
+
+        let mut evicted = vec![];
+        while self.size > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, _, s)) = self.entries.remove(&oldest) {
+                self.size -= s;
+            }
+            evicted.push(oldest);
+        }
+        evicted
+    }
+
+    fn remove(&mut self, key: &ChunkKey) {
+        if let Some((_, last_used, s)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+            self.size -= s;
+        }
+    }
+}
+
+/// Two-tier cache of verified chunks in front of the store: an in-memory LRU and an
+/// optional LRU of chunk files in a local directory. Chunks read back from disk are
+/// verified again before being served.
+#[derive(Debug)]
+pub struct ChunkCache {
+    memory: Mutex<Lru<Bytes>>,
+    disk: Option<(PathBuf, Mutex<Lru<()>>)>,
+}
+
+/// Subdirectory of the configured cache directory holding the chunk files; the cache owns it
+/// and clears it at startup, leaving anything else in the directory alone
+const CHUNK_CACHE_SUBDIR: &str = "file-service-chunks";
+
+impl ChunkCache {
+    /// Build a cache from server configurations; `None` if no tier has capacity
+    pub fn from_config(config: &ServerArgs) -> Result<Option<Self>, Error> {
+        Self::new(
+            config.chunk_cache_memory_bytes,
+            config.chunk_cache_dir.as_deref().map(std::path::Path::new),
+            config.chunk_cache_disk_bytes,
+        )
+    }
+
+    /// Build a cache with the tier capacities in bytes; the disk tier needs a directory
+    pub fn new(
+        memory_bytes: u64,
+        dir: Option<&std::path::Path>,
+        disk_bytes: u64,
+    ) -> Result<Option<Self>, Error> {
+        let disk = match dir {
+            Some(dir) if disk_bytes > 0 => {
+                let dir = dir.join(CHUNK_CACHE_SUBDIR);
+                // Stale chunk files from a previous run are not tracked, start clean
+                if dir.exists() {
+                    std::fs::remove_dir_all(&dir).map_err(|e| {
+                        Error::InvalidConfig(format!(
+                            "Cannot clear chunk cache directory {}: {}",
+                            dir.display(),
+                            e
+                        ))
+                    })?;
+                }
+                std::fs::create_dir_all(&dir).map_err(|e| {
+                    Error::InvalidConfig(format!(
+                        "Cannot create chunk cache directory {}: {}",
+                        dir.display(),
+                        e
+                    ))
+                })?;
+                Some((dir, Mutex::new(Lru::new(disk_bytes as usize))))
+            }
+            _ => None,
+        };
+        if memory_bytes == 0 && disk.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(ChunkCache {
+            memory: Mutex::new(Lru::new(memory_bytes as usize)),
+            disk,
+        }))
+    }
+
+    fn chunk_path(dir: &std::path::Path, (file_hash, index): &ChunkKey) -> PathBuf {
+        dir.join(file_hash).join(index.to_string())
+    }
+
+    /// Get a chunk, checking memory then disk. Disk hits are promoted to memory.
+    pub async fn get(&self, key: &ChunkKey, chunk_hash: &str) -> Option<Bytes> {
+        if let Some(data) = self.memory.lock().unwrap().get(key) {
+            CHUNK_CACHE_HITS.with_label_values(&["memory"]).inc();
+            return Some(data);
+        }
+
+        if let Some((dir, index)) = &self.disk {
+            if index.lock().unwrap().get(key).is_some() {
+                let path = Self::chunk_path(dir, key);
+                match tokio::fs::read(&path).await.map(Bytes::from) {
+                    Ok(data) if verify_chunk(&data, chunk_hash) => {
+                        CHUNK_CACHE_HITS.with_label_values(&["disk"]).inc();
+                        self.insert_memory(key.clone(), data.clone());
+                        return Some(data);
+                    }
+                    _ => {
+                        tracing::warn!(
+                            path = tracing::field::debug(&path),
+                            "Drop invalid cached chunk"
+                        );
+                        index.lock().unwrap().remove(key);
+                        let _ = tokio::fs::remove_file(&path).await;
+                    }
+                }
+            }
+        }
+
+        CHUNK_CACHE_MISSES.inc();
+        None
+    }
+
+    /// Insert a chunk that has been verified against its hash
+    pub async fn insert(&self, key: ChunkKey, data: Bytes) {
+        self.insert_memory(key.clone(), data.clone());
+
+        if let Some((dir, index)) = &self.disk {
+            if data.len() > index.lock().unwrap().capacity {
+                return;
+            }
+            let path = Self::chunk_path(dir, &key);
+            if let Some(parent) = path.parent() {
+                let _ = tokio::fs::create_dir_all(parent).await;
+            }
+            if let Err(e) = tokio::fs::write(&path, &data).await {
+                tracing::warn!(error = e.to_string(), "Failed to write chunk to disk cache");
+                return;
+            }
+            let evicted = index.lock().unwrap().insert(key, (), data.len());
+            for key in evicted {
+                let _ = tokio::fs::remove_file(Self::chunk_path(dir, &key)).await;
+            }
+            CHUNK_CACHE_BYTES
+                .with_label_values(&["disk"])
+                .set(index.lock().unwrap().size as i64);
+        }
+    }
+
+    fn insert_memory(&self, key: ChunkKey, data: Bytes) {
+        let mut memory = self.memory.lock().unwrap();
+        let size = data.len();
+        memory.insert(key, data, size);
+        CHUNK_CACHE_BYTES
+            .with_label_values(&["memory"])
+            .set(memory.size as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use file_exchange::manifest::file_hasher::hash_chunk;
+
+    fn key(index: u64) -> ChunkKey {
+        ("QmFile".to_string(), index)
+    }
+
+    #[test]
+    fn test_lru_hits_and_misses() {
+        let mut lru = Lru::new(10);
+        assert_eq!(lru.get(&key(0)), None);
+        lru.insert(key(0), 0, 4);
+        lru.insert(key(1), 1, 4);
+        assert_eq!(lru.get(&key(0)), Some(0));
+        assert_eq!(lru.get(&key(1)), Some(1));
+        assert_eq!(lru.get(&key(2)), None);
+
+        // Re-inserting an entry replaces it without counting its size twice
+        lru.insert(key(1), 10, 4);
+        assert_eq!(lru.get(&key(1)), Some(10));
+        assert_eq!(lru.size, 8);
+        lru.remove(&key(1));
+        assert_eq!(lru.get(&key(1)), None);
+        assert_eq!(lru.size, 4);
+    }
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let mut lru = Lru::new(10);
+        lru.insert(key(0), 0, 4);
+        lru.insert(key(1), 1, 4);
+        // Using the first entry leaves the second as the least recently used
+        lru.get(&key(0));
+        assert_eq!(lru.insert(key(2), 2, 4), vec![key(1)]);
+        assert_eq!(lru.get(&key(1)), None);
+        assert_eq!(lru.size, 8);
+
+        // A large entry evicts as many entries as needed
+        assert_eq!(lru.insert(key(3), 3, 9), vec![key(0), key(2)]);
+        assert_eq!(lru.size, 9);
+        // Entries larger than the capacity are not cached
+        assert!(lru.insert(key(4), 4, 11).is_empty());
+        assert_eq!(lru.get(&key(4)), None);
+        assert_eq!(lru.get(&key(3)), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_disk_tier_in_own_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let unrelated = dir.path().join("unrelated");
+        std::fs::write(&unrelated, b"keep").unwrap();
+
+        let cache = ChunkCache::new(0, Some(dir.path()), 8).unwrap().unwrap();
+        let chunk = Bytes::from_static(b"abcd");
+        let chunk_hash = hash_chunk(&chunk);
+        assert_eq!(cache.get(&key(0), &chunk_hash).await, None);
+        cache.insert(key(0), chunk.clone()).await;
+        assert_eq!(cache.get(&key(0), &chunk_hash).await, Some(chunk));
+
+        // Restarting clears the cached chunks but nothing else in the directory
+        let cache = ChunkCache::new(0, Some(dir.path()), 8).unwrap().unwrap();
+        assert_eq!(cache.get(&key(0), &chunk_hash).await, None);
+        assert_eq!(std::fs::read(&unrelated).unwrap(), b"keep");
+    }
+
+    #[test]
+    fn test_no_cache_without_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(ChunkCache::new(0, Some(dir.path()), 0).unwrap().is_none());
+        assert!(ChunkCache::new(0, None, 8).unwrap().is_none());
+        // The cache directory must be usable
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(ChunkCache::new(0, Some(&file), 8).is_err());
+    }
+}
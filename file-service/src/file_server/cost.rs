@@ -4,7 +4,7 @@ use axum::extract::State;
 use file_exchange::manifest::Bundle;
 use serde::{Deserialize, Serialize};
 
-use crate::file_server::ServerContext;
+use crate::file_server::{receipt::bundle_price, ServerContext};
 
 #[derive(Clone, Debug, Serialize, Deserialize, SimpleObject)]
 pub struct GraphQlCostModel {
@@ -23,19 +23,15 @@ impl PriceQuery {
         ctx: &Context<'_>,
         deployments: Vec<String>,
     ) -> Result<Vec<GraphQlCostModel>, anyhow::Error> {
-        let price: f64 = ctx
-            .data_unchecked::<ServerContext>()
-            .state
-            .config
-            .server
-            .default_price_per_byte;
-        let cost_models = deployments
-            .into_iter()
-            .map(|s| GraphQlCostModel {
-                deployment: s,
+        let state = &ctx.data_unchecked::<ServerContext>().state;
+        let mut cost_models = vec![];
+        for deployment in deployments {
+            let price = bundle_price(state, &deployment).await;
+            cost_models.push(GraphQlCostModel {
+                deployment,
                 price_per_byte: price,
-            })
-            .collect::<Vec<GraphQlCostModel>>();
+            });
+        }
         Ok(cost_models)
     }
 
@@ -54,19 +50,15 @@ impl PriceQuery {
             .get(&deployment)
             .cloned()
             .map(|b| b.bundle);
-        let res = bundle.map(|_b| {
-            let price: f64 = ctx
-                .data_unchecked::<ServerContext>()
-                .state
-                .config
-                .server
-                .default_price_per_byte;
-            GraphQlCostModel {
-                deployment,
-                price_per_byte: price,
-            }
-        });
-        Ok(res)
+        if bundle.is_none() {
+            return Ok(None);
+        }
+        // Quote the price receipts are checked against
+        let price = bundle_price(&ctx.data_unchecked::<ServerContext>().state, &deployment).await;
+        Ok(Some(GraphQlCostModel {
+            deployment,
+            price_per_byte: price,
+        }))
     }
 }
 
@@ -19,16 +19,20 @@ use crate::{config::Config, database};
 use file_exchange::manifest::{
     ipfs::IpfsClient, manifest_fetcher::read_bundle, validate_bundle_entries, LocalBundle,
 };
+use file_exchange::throttle::Throttle;
 use file_exchange::util::public_key;
 use file_exchange::{errors::Error, manifest::store::Store};
 // #![cfg(feature = "acceptor")]
 // use hyper_rustls::TlsAcceptor;
 use hyper::StatusCode;
 
+pub mod cache;
 pub mod cost;
 pub mod range;
+pub mod receipt;
 pub mod service;
 pub mod status;
+pub mod storage;
 pub mod util;
 
 #[derive(Clone)]
@@ -36,13 +40,17 @@ pub struct ServerState {
     pub client: IpfsClient,
     pub operator_public_key: String,
     pub bundles: Arc<Mutex<HashMap<String, LocalBundle>>>, // Keyed by IPFS hash, valued by Bundle and Local path
+    pub quarantined_bundles: Arc<Mutex<HashMap<String, LocalBundle>>>, // Bundles failing verification, not served
     pub prices: Arc<Mutex<HashMap<String, f64>>>, // Keyed by IPFS hash, valued by price per byte
     pub admin_auth_token: Option<String>,         // Add bearer prefix
     pub config: Config,
     pub database: PgPool,
     pub cost_schema: crate::file_server::cost::CostSchema,
     pub status_schema: crate::file_server::status::StatusSchema,
-    pub store: Store,
+    pub stores: storage::BundleStores,
+    pub chunk_cache: Option<Arc<cache::ChunkCache>>, // Hot chunks of object storage
+    pub throttle: Arc<Throttle>,                     // Bandwidth limits in total and per consumer
+    pub receipts: Arc<receipt::ReceiptVerifier>,     // Escrow accounts and allocations paid to
 }
 
 #[derive(Clone)]
@@ -102,7 +110,8 @@ pub async fn initialize_server_context(config: Config) -> Result<ServerContext,
         "Validated bundle entries"
     );
 
-    let store = Store::new(&config.server.storage_method)?;
+    let stores = storage::BundleStores::new(&config.server)?;
+    let chunk_cache = cache::ChunkCache::from_config(&config.server)?.map(Arc::new);
 
     let admin_auth_token = config
         .server
@@ -117,6 +126,7 @@ pub async fn initialize_server_context(config: Config) -> Result<ServerContext,
         config: config.clone(),
         client: client.clone(),
         bundles: Arc::new(Mutex::new(HashMap::new())),
+        quarantined_bundles: Arc::new(Mutex::new(HashMap::new())),
         prices: Arc::new(Mutex::new(HashMap::new())),
         admin_auth_token,
         operator_public_key: public_key(&config.common.indexer.operator_mnemonic)
@@ -124,24 +134,84 @@ pub async fn initialize_server_context(config: Config) -> Result<ServerContext,
         database: database::connect(&config.common.database.postgres_url).await,
         cost_schema: cost::build_schema().await,
         status_schema: status::build_schema().await,
-        store,
+        stores,
+        chunk_cache,
+        throttle: Arc::new(Throttle::new(
+            config.server.max_serve_rate,
+            config.server.max_consumer_serve_rate,
+        )),
+        receipts: Arc::new(receipt::ReceiptVerifier::new(&config)),
     };
 
     // Fetch the file using IPFS client
-    for (ipfs_hash, local_path) in bundle_entries {
+    for (ipfs_hash, location) in bundle_entries {
         let bundle = read_bundle(&server_state.client, &ipfs_hash).await?;
+        let (store, local_path) = server_state.stores.resolve(&location).await?;
+        let local = LocalBundle { bundle, local_path };
 
+        // Files verified previously and unchanged in the store are not re-hashed
+        if let Err(e) = validate_bundle(&store, &server_state.database, &local).await {
+            tracing::error!(
+                bundle = ipfs_hash,
+                error = e.to_string(),
+                "Failed to validate bundle, skip serving"
+            );
+            continue;
+        }
+
+        server_state.stores.assign(&ipfs_hash, store).await;
         server_state
             .bundles
             .lock()
             .await
-            .insert(bundle.ipfs_hash.clone(), LocalBundle { bundle, local_path });
+            .insert(local.bundle.ipfs_hash.clone(), local);
     }
 
     // Return the server state wrapped in an Arc for thread safety
     Ok(ServerContext::new(Arc::new(server_state)))
 }
 
+/// Validate the served files of a bundle against their chunk hashes. Files with a
+/// verification record matching the current object (location, e_tag, last modified, size)
+/// are trusted; other files are re-hashed and recorded once verified.
+pub async fn validate_bundle(
+    store: &Store,
+    database: &PgPool,
+    local: &LocalBundle,
+) -> Result<(), Error> {
+    let bundle_hash = &local.bundle.ipfs_hash;
+    for file_meta in &local.bundle.file_manifests {
+        let file_hash = &file_meta.meta_info.hash;
+        let object = store
+            .object_meta(&file_meta.meta_info.name, &local.local_path)
+            .await?;
+
+        match database::is_verified(database, bundle_hash, file_hash, &object).await {
+            Ok(true) => {
+                tracing::trace!(bundle_hash, file_hash, "File previously verified, skip");
+                continue;
+            }
+            Ok(false) => {}
+            Err(e) => tracing::warn!(
+                error = e.to_string(),
+                "Cannot read verification records, re-validating file"
+            ),
+        };
+
+        store
+            .read_and_validate_file(file_meta, &local.local_path)
+            .await?;
+
+        if let Err(e) =
+            database::record_verification(database, bundle_hash, file_hash, &object).await
+        {
+            tracing::warn!(error = e.to_string(), "Failed to record file verification");
+        }
+    }
+    tracing::debug!(bundle_hash, "Validated bundle");
+    Ok(())
+}
+
 #[derive(Debug, Error)]
 pub enum FileServiceError {
     #[error("Invalid status query: {0}")]
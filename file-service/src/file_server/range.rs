@@ -2,16 +2,21 @@
 use hyper::header::{CONTENT_LENGTH, CONTENT_RANGE};
 use hyper::{Body, Response, StatusCode};
 
+use bytes::Bytes;
 use object_store::path::Path;
 use std::io::Read;
+use std::sync::Arc;
 
 use serde_json::Value;
 
 use file_exchange::{
     errors::{Error, ServerError},
-    manifest::store::Store,
+    manifest::{file_hasher::verify_chunk, store::Store, FileManifestMeta},
+    throttle::Throttle,
 };
 
+use super::cache::ChunkCache;
+
 // Function to parse the Range header and return the start and end bytes
 pub fn parse_range_header(range_header: &Value) -> Result<(usize, usize), Error> {
     let range_str = range_header
@@ -37,16 +42,26 @@ pub fn parse_range_header(range_header: &Value) -> Result<(usize, usize), Error>
     let end = ranges[1]
         .parse::<usize>()
         .map_err(|e| Error::InvalidRange(format!("Invalid end range: {}", e)))?;
+    if end < start {
+        return Err(Error::InvalidRange(format!(
+            "Range end {} is before its start {}",
+            end, start
+        )));
+    }
 
     Ok((start, end))
 }
 
 pub async fn serve_file_range(
     store: Store,
-    file_name: &str,
+    chunk_cache: Option<&ChunkCache>,
+    file_manifest: &FileManifestMeta,
     file_prefix: &Path,
     (start, end): (usize, usize),
+    throttle: &Arc<Throttle>,
+    consumer: &str,
 ) -> Result<Response<Body>, Error> {
+    let file_name = &file_manifest.meta_info.name;
     tracing::debug!(
         file_name = tracing::field::debug(&file_name),
         file_prefix = tracing::field::debug(&file_prefix),
@@ -55,33 +70,10 @@ pub async fn serve_file_range(
         "Serve file range"
     );
 
-    let metadata = store
-        .find_object(file_name, Some(file_prefix))
-        .await
-        .ok_or(Error::DataUnavailable(format!(
-            "Cannot find object {} with prefix {}",
-            file_name, file_prefix
-        )))?;
-
-    let file_size = metadata.size;
-    tracing::debug!(
-        metadata = tracing::field::debug(&metadata),
-        file_size = tracing::field::debug(&file_size),
-        "Serve file range"
-    );
-
-    tracing::trace!(start, end, file_size, "Range validity check");
-    if start >= file_size || end >= file_size {
-        return Ok(Response::builder()
-            .status(StatusCode::RANGE_NOT_SATISFIABLE)
-            .body(
-                format!(
-                    "Range ({:#?}, {:#?}) out of bound for file size {:#?}",
-                    start, end, file_size
-                )
-                .into(),
-            )
-            .unwrap());
+    // The manifest gives the file size, so ranges are checked without a round-trip to the
+    // store and cached chunks are served without touching it
+    if let Some(response) = range_not_satisfiable(file_manifest, (start, end)) {
+        return Ok(response);
     }
 
     let length = end - start + 1;
@@ -89,7 +81,12 @@ pub async fn serve_file_range(
         start,
         end: start + length,
     };
-    let content = store.range_read(file_name, &range).await?;
+    let content = match (chunk_cache, chunk_indices(file_manifest, start, end)) {
+        (Some(cache), Some(indices)) => {
+            read_cached_chunks(&store, cache, file_manifest, file_prefix, indices).await?
+        }
+        _ => store.range_read(file_name, file_prefix, &range).await?,
+    };
 
     let transferred_bytes = crate::metrics::TRANSFERRED_BYTES.with_label_values(&[file_name]);
     transferred_bytes.set(length.try_into().unwrap());
@@ -97,21 +94,290 @@ pub async fn serve_file_range(
         .status(StatusCode::PARTIAL_CONTENT)
         .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, length))
         .header(CONTENT_LENGTH, length.to_string())
-        .body(Body::from(content))
+        .body(throttled_body(content, throttle, consumer))
         .map_err(|e| Error::ServerError(ServerError::BuildResponseError(e.to_string())))
 }
 
+/// Response refusing a range that does not lie within the file of the manifest
+pub fn range_not_satisfiable(
+    file_manifest: &FileManifestMeta,
+    (start, end): (usize, usize),
+) -> Option<Response<Body>> {
+    let file_size = file_manifest.file_manifest.total_bytes;
+    tracing::trace!(start, end, file_size, "Range validity check");
+    if (start as u64) < file_size && (end as u64) < file_size {
+        return None;
+    }
+    Some(
+        Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .body(
+                format!(
+                    "Range ({:#?}, {:#?}) out of bound for file size {:#?}",
+                    start, end, file_size
+                )
+                .into(),
+            )
+            .unwrap(),
+    )
+}
+
+/// Indices of the chunks exactly covered by the inclusive byte range, if the range starts and
+/// ends on chunk boundaries
+fn chunk_indices(
+    file_manifest: &FileManifestMeta,
+    start: usize,
+    end: usize,
+) -> Option<std::ops::Range<u64>> {
+    let manifest = &file_manifest.file_manifest;
+    let (start, end) = (start as u64, end as u64);
+    if manifest.chunk_size == 0
+        || end < start
+        || end >= manifest.total_bytes
+        || start % manifest.chunk_size != 0
+    {
+        return None;
+    }
+    let last = end / manifest.chunk_size;
+    let chunk_end = ((last + 1) * manifest.chunk_size).min(manifest.total_bytes) - 1;
+    (end == chunk_end && (last as usize) < manifest.chunk_hashes.len())
+        .then_some(start / manifest.chunk_size..last + 1)
+}
+
+/// Serve a span of chunks, looking up each chunk in the cache
+async fn read_cached_chunks(
+    store: &Store,
+    cache: &ChunkCache,
+    file_manifest: &FileManifestMeta,
+    file_prefix: &Path,
+    indices: std::ops::Range<u64>,
+) -> Result<Bytes, Error> {
+    let mut chunks = Vec::with_capacity(indices.end.saturating_sub(indices.start) as usize);
+    for index in indices {
+        chunks.push(read_cached_chunk(store, cache, file_manifest, file_prefix, index).await?);
+    }
+    match chunks.len() {
+        1 => Ok(chunks.remove(0)),
+        _ => Ok(Bytes::from(chunks.concat())),
+    }
+}
+
+/// Serve a chunk from the cache, or read it from the store and cache it once verified
+async fn read_cached_chunk(
+    store: &Store,
+    cache: &ChunkCache,
+    file_manifest: &FileManifestMeta,
+    file_prefix: &Path,
+    index: u64,
+) -> Result<Bytes, Error> {
+    let key = (file_manifest.meta_info.hash.clone(), index);
+    let manifest = &file_manifest.file_manifest;
+    let chunk_hash = &manifest.chunk_hashes[index as usize];
+    if let Some(content) = cache.get(&key, chunk_hash).await {
+        return Ok(content);
+    }
+
+    let start = index * manifest.chunk_size;
+    let range = std::ops::Range {
+        start: start as usize,
+        end: (start + manifest.chunk_size).min(manifest.total_bytes) as usize,
+    };
+    let content = store
+        .range_read(&file_manifest.meta_info.name, file_prefix, &range)
+        .await?;
+    if verify_chunk(&content, chunk_hash) {
+        cache.insert(key, content.clone()).await;
+    } else {
+        tracing::warn!(
+            file_name = file_manifest.meta_info.name,
+            chunk = index,
+            "Stored chunk does not match its manifest hash, not caching"
+        );
+    }
+    Ok(content)
+}
+
 pub async fn serve_file(
     store: Store,
     file_name: &str,
-    _file_path: &Path,
+    file_prefix: &Path,
+    throttle: &Arc<Throttle>,
+    consumer: &str,
 ) -> Result<Response<Body>, Error> {
     // If no Range header is present, serve the entire file
-    let mut file = store.read(file_name).await?;
+    let mut file = store.read(file_name, file_prefix).await?;
     let mut contents = Vec::new();
     file.read_to_end(&mut contents)
         .map_err(Error::FileIOError)?;
     Response::builder()
-        .body(Body::from(contents))
+        .body(throttled_body(contents.into(), throttle, consumer))
         .map_err(|e| Error::ServerError(ServerError::BuildResponseError(e.to_string())))
 }
+
+/// Bytes sent to a consumer between bandwidth checks
+const THROTTLE_SLICE: usize = 64 * 1024;
+
+/// Stream content to a consumer within the bandwidth limits, in total and per consumer
+fn throttled_body(content: Bytes, throttle: &Arc<Throttle>, consumer: &str) -> Body {
+    if throttle.limits() == (0, 0) {
+        return Body::from(content);
+    }
+    let (mut sender, body) = Body::channel();
+    let throttle = throttle.clone();
+    let consumer = consumer.to_string();
+    tokio::spawn(async move {
+        let mut content = content;
+        while !content.is_empty() {
+            let slice = content.split_to(content.len().min(THROTTLE_SLICE));
+            throttle.acquire(&consumer, slice.len() as u64).await;
+            // The consumer went away
+            if sender.send_data(slice).await.is_err() {
+                break;
+            }
+        }
+    });
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use file_exchange::{
+        config::{LocalDirectory, StorageMethod},
+        manifest::{file_hasher::hash_chunk, FileManifest, FileMetaInfo},
+    };
+
+    const CHUNK_SIZE: usize = 4;
+
+    fn file_meta(bytes: &[u8]) -> FileManifestMeta {
+        FileManifestMeta {
+            meta_info: FileMetaInfo {
+                name: "data.bin".to_string(),
+                hash: "QmFile".to_string(),
+            },
+            file_manifest: FileManifest {
+                total_bytes: bytes.len() as u64,
+                chunk_size: CHUNK_SIZE as u64,
+                chunk_hashes: bytes.chunks(CHUNK_SIZE).map(hash_chunk).collect(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_parse_range_header() {
+        assert_eq!(
+            parse_range_header(&Value::from("bytes=0-1023")).unwrap(),
+            (0, 1023)
+        );
+        assert_eq!(
+            parse_range_header(&Value::from("bytes=5-5")).unwrap(),
+            (5, 5)
+        );
+        for invalid in ["bytes=10-9", "bytes=0-", "0-10", "bytes=a-10"] {
+            assert!(matches!(
+                parse_range_header(&Value::from(invalid)),
+                Err(Error::InvalidRange(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_range_not_satisfiable() {
+        let meta = file_meta(b"aaaabbbbcc");
+        assert!(range_not_satisfiable(&meta, (0, 9)).is_none());
+        for range in [(0, 10), (10, 10), (12, 20)] {
+            let response = range_not_satisfiable(&meta, range).unwrap();
+            assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        }
+    }
+
+    #[test]
+    fn test_chunk_indices() {
+        let meta = file_meta(b"aaaabbbbcc");
+        assert_eq!(chunk_indices(&meta, 0, 3), Some(0..1));
+        assert_eq!(chunk_indices(&meta, 4, 9), Some(1..3));
+        assert_eq!(chunk_indices(&meta, 0, 9), Some(0..3));
+        // Ranges not on chunk boundaries, reversed, or past the end are read from the store
+        assert_eq!(chunk_indices(&meta, 1, 3), None);
+        assert_eq!(chunk_indices(&meta, 0, 5), None);
+        assert_eq!(chunk_indices(&meta, 4, 3), None);
+        assert_eq!(chunk_indices(&meta, 8, 10), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_cached_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
+            main_dir: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        }))
+        .unwrap();
+        let bytes = b"aaaabbbbcc";
+        store.write("bundle/data.bin", bytes).await.unwrap();
+        let meta = file_meta(bytes);
+        let prefix = Path::from("bundle");
+        let cache = ChunkCache::new(1024, None, 0).unwrap().unwrap();
+
+        // A span misses the cache and is read chunk by chunk from the store
+        let content = read_cached_chunks(&store, &cache, &meta, &prefix, 1..3)
+            .await
+            .unwrap();
+        assert_eq!(&content[..], b"bbbbcc");
+
+        // Cached chunks are served without the store, a missing chunk is still read from it
+        store.delete("bundle/data.bin").await.unwrap();
+        let content = read_cached_chunks(&store, &cache, &meta, &prefix, 1..3)
+            .await
+            .unwrap();
+        assert_eq!(&content[..], b"bbbbcc");
+        assert!(read_cached_chunks(&store, &cache, &meta, &prefix, 0..2)
+            .await
+            .is_err());
+    }
+
+    async fn serve(
+        store: &Store,
+        cache: &ChunkCache,
+        meta: &FileManifestMeta,
+        range: (usize, usize),
+    ) -> Response<Body> {
+        let throttle = Arc::new(Throttle::default());
+        serve_file_range(
+            store.clone(),
+            Some(cache),
+            meta,
+            &Path::from(""),
+            range,
+            &throttle,
+            "consumer",
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serve_cached_range_without_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(&StorageMethod::LocalFiles(LocalDirectory {
+            main_dir: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        }))
+        .unwrap();
+        let bytes = b"aaaabbbbcc";
+        store.write("data.bin", bytes).await.unwrap();
+        let meta = file_meta(bytes);
+        let cache = ChunkCache::new(1024, None, 0).unwrap().unwrap();
+
+        let response = serve(&store, &cache, &meta, (4, 9)).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+
+        // Once cached, the range is served and bounds checked without the store
+        store.delete("data.bin").await.unwrap();
+        let response = serve(&store, &cache, &meta, (4, 9)).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"bbbbcc");
+        let response = serve(&store, &cache, &meta, (4, 10)).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+}
@@ -0,0 +1,490 @@
+use serde_json::Value;
+use std::time::{Duration, SystemTime};
+
+use file_exchange::{
+    discover::cache::FetchCache,
+    download_client::signer::{recover_signer, TapReceipt},
+    errors::Error,
+    graphql::{escrow_query::escrow_account, network_query::active_allocations},
+    util::GRT,
+};
+
+use super::ServerState;
+use crate::{config::Config, database};
+
+/// Why a request was not paid for
+#[derive(Debug)]
+pub enum PaymentError {
+    /// The receipt does not pay for the request; sending it again cannot succeed
+    Rejected(String),
+    /// The receipt could not be checked, such as while a subgraph or the database is down
+    Unverified(Error),
+}
+
+/// Checks of receipts against the escrow accounts of their signers and the allocations of the
+/// indexer, which are looked up in the subgraphs and cached for their syncing intervals
+pub struct ReceiptVerifier {
+    http_client: reqwest::Client,
+    escrow_balances: FetchCache<Option<u128>>,
+    allocations: FetchCache<Vec<String>>,
+}
+
+impl ReceiptVerifier {
+    pub fn new(config: &Config) -> Self {
+        ReceiptVerifier {
+            http_client: reqwest::Client::new(),
+            escrow_balances: FetchCache::new(Some(Duration::from_secs(
+                config.common.escrow_subgraph.syncing_interval,
+            ))),
+            allocations: FetchCache::new(Some(Duration::from_secs(
+                config.common.network_subgraph.syncing_interval,
+            ))),
+        }
+    }
+
+    /// Escrow balance in wei a sender deposited for the operator, if there is an account
+    async fn escrow_balance(
+        &self,
+        state: &ServerState,
+        sender: &str,
+    ) -> Result<Option<u128>, Error> {
+        self.escrow_balances
+            .get_or_fetch(sender, || async {
+                let account = escrow_account(
+                    &self.http_client,
+                    &state.config.common.escrow_subgraph.query_url,
+                    sender,
+                    &state.operator_public_key,
+                )
+                .await?;
+                account
+                    .map(|account| {
+                        account.balance.parse::<u128>().map_err(|e| {
+                            Error::DataUnavailable(format!(
+                                "Invalid escrow balance {}: {}",
+                                account.balance, e
+                            ))
+                        })
+                    })
+                    .transpose()
+            })
+            .await
+    }
+
+    /// Active allocations of the indexer on a deployment, as lowercase hex
+    async fn allocations(
+        &self,
+        state: &ServerState,
+        deployment: &str,
+    ) -> Result<Vec<String>, Error> {
+        self.allocations
+            .get_or_fetch(deployment, || async {
+                let indexer = format!("{:#x}", state.config.common.indexer.indexer_address);
+                let allocations = active_allocations(
+                    &self.http_client,
+                    &state.config.common.network_subgraph.query_url,
+                    deployment,
+                )
+                .await?;
+                Ok(allocations
+                    .into_iter()
+                    .filter(|a| a.indexer.id.to_lowercase() == indexer)
+                    .map(|a| a.id.to_lowercase())
+                    .collect())
+            })
+            .await
+    }
+}
+
+/// Price per byte of a bundle; prices set through the admin API take precedence over the
+/// default price
+pub async fn bundle_price(state: &ServerState, deployment: &str) -> f64 {
+    state
+        .prices
+        .lock()
+        .await
+        .get(deployment)
+        .copied()
+        .unwrap_or(state.config.server.default_price_per_byte)
+}
+
+/// Verify and store the receipt paying for a request of a number of bytes, returning the
+/// signer of the receipt. The receipt must be signed by a sender whose escrow account for the
+/// operator covers it on top of what the sender owes already, be issued against an active
+/// allocation of the indexer on the bundle, pay for the bytes at the bundle's price, be recent,
+/// and not have been redeemed before. Requests without a receipt are only accepted as free
+/// queries authorized by the server's free query auth token, and have no signer.
+pub async fn verify_payment(
+    state: &ServerState,
+    deployment: &str,
+    req: &Value,
+    bytes: u64,
+) -> Result<Option<String>, PaymentError> {
+    let receipt = match req.get("receipt").and_then(|r| r.as_str()) {
+        Some(receipt) => receipt,
+        None => {
+            let token = state.config.common.server.free_query_auth_token.as_deref();
+            if !free_query_authorized(req, token) {
+                return Err(PaymentError::Rejected("Missing receipt".to_string()));
+            }
+            return Ok(None);
+        }
+    };
+    let receipt: TapReceipt = serde_json::from_str(receipt)
+        .map_err(|e| PaymentError::Rejected(format!("Invalid receipt: {}", e)))?;
+    let message = &receipt.message;
+
+    let price = bundle_price(state, deployment).await;
+    let fee = GRT::for_bytes(price, bytes)
+        .ok()
+        .and_then(|fee| fee.wei())
+        .ok_or_else(|| {
+            PaymentError::Rejected(format!("Cannot price {} bytes at {}", bytes, price))
+        })?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let scalar = &state.config.common.scalar;
+    let sender = check_receipt(
+        &receipt,
+        fee,
+        now,
+        Duration::from_secs(state.config.server.receipt_max_age),
+        scalar.chain_id,
+        &format!("{:#x}", scalar.receipts_verifier_address),
+    )?;
+
+    let signer = sender.trim_start_matches("0x");
+    let allocation = hex::encode(message.allocation_id);
+    let balance = state
+        .receipts
+        .escrow_balance(state, &sender)
+        .await
+        .map_err(PaymentError::Unverified)?;
+    let outstanding =
+        database::outstanding_value(&state.database, signer, &allocation, message.nonce)
+            .await
+            .map_err(|e| PaymentError::Unverified(Error::DataUnavailable(e.to_string())))?;
+    check_escrow(&sender, balance, message.value, outstanding)?;
+
+    let allocations = state
+        .receipts
+        .allocations(state, deployment)
+        .await
+        .map_err(PaymentError::Unverified)?;
+    check_allocation(&receipt, deployment, &allocations)?;
+
+    // Storing the receipt claims its nonce, so concurrent replays cannot both pass
+    let stored = database::store_receipt(&state.database, signer, &receipt)
+        .await
+        .map_err(|e| PaymentError::Unverified(Error::DataUnavailable(e.to_string())))?;
+    if !stored {
+        return Err(PaymentError::Rejected(format!(
+            "Receipt nonce {} already redeemed on allocation 0x{}",
+            message.nonce, allocation
+        )));
+    }
+    tracing::trace!(
+        sender,
+        allocation,
+        value = message.value,
+        bytes,
+        "Accepted receipt"
+    );
+    Ok(Some(sender))
+}
+
+/// Whether a request without a receipt carries the free query auth token of the server, as
+/// sent in the authorization header with or without the bearer scheme
+fn free_query_authorized(req: &Value, token: Option<&str>) -> bool {
+    let authorization = req.get("authorization").and_then(|a| a.as_str());
+    match (authorization, token) {
+        (Some(authorization), Some(token)) => {
+            authorization
+                .strip_prefix("Bearer ")
+                .unwrap_or(authorization)
+                == token
+        }
+        _ => false,
+    }
+}
+
+/// Checks of a receipt on its own: its value covers the fee, it was issued within the max age
+/// around the current time, and it is signed under the domain of the chain and receipts
+/// verifier. Returns the signer as lowercase hex.
+fn check_receipt(
+    receipt: &TapReceipt,
+    fee: u128,
+    now_ns: u128,
+    max_age: Duration,
+    chain_id: u64,
+    verifier: &str,
+) -> Result<String, PaymentError> {
+    let message = &receipt.message;
+    if message.value < fee {
+        return Err(PaymentError::Rejected(format!(
+            "Receipt value {} is below the price {}",
+            message.value, fee
+        )));
+    }
+
+    // Older receipts could no longer be told apart from replays once aggregated
+    let timestamp = message.timestamp_ns as u128;
+    let max_age_ns = max_age.as_nanos();
+    if timestamp + max_age_ns < now_ns || timestamp > now_ns + max_age_ns {
+        return Err(PaymentError::Rejected(format!(
+            "Receipt timestamp {} is outside of {} seconds from now",
+            timestamp,
+            max_age.as_secs()
+        )));
+    }
+
+    // A receipt signed by another key or under another domain recovers to another signer,
+    // which is then rejected for its escrow account
+    let signer = recover_signer(receipt, chain_id, verifier)
+        .map_err(|e| PaymentError::Rejected(format!("Invalid receipt signature: {}", e)))?;
+    Ok(format!("{:#x}", signer))
+}
+
+/// The escrow balance of the sender must cover the receipt on top of the value the sender
+/// owes already, all in wei
+fn check_escrow(
+    sender: &str,
+    balance: Option<u128>,
+    value: u128,
+    outstanding: u128,
+) -> Result<(), PaymentError> {
+    let required = value.checked_add(outstanding).ok_or_else(|| {
+        PaymentError::Rejected(format!(
+            "Receipt value {} and {} owed by {} overflow",
+            value, outstanding, sender
+        ))
+    })?;
+    match balance {
+        Some(balance) if balance >= required => Ok(()),
+        Some(balance) => Err(PaymentError::Rejected(format!(
+            "Escrow balance {} of receipt signer {} does not cover {} owed",
+            balance, sender, required
+        ))),
+        None => Err(PaymentError::Rejected(format!(
+            "Receipt signer {} has no escrow account",
+            sender
+        ))),
+    }
+}
+
+/// The receipt must be issued against an active allocation of the indexer on the deployment
+fn check_allocation(
+    receipt: &TapReceipt,
+    deployment: &str,
+    allocations: &[String],
+) -> Result<(), PaymentError> {
+    let allocation = format!("{:#x}", receipt.message.allocation_id);
+    if !allocations.contains(&allocation) {
+        return Err(PaymentError::Rejected(format!(
+            "Allocation {} is not an active allocation on {}",
+            allocation, deployment
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+    use file_exchange::test_util::signed_receipt;
+    use rand::RngCore;
+    use serde_json::json;
+    use sqlx::types::BigDecimal;
+    use std::str::FromStr;
+
+    const PRIVATE_KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const OTHER_KEY: &str = "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+    const CHAIN_ID: u64 = 421614;
+    const VERIFIER: &str = "0xfc24ce7a4428a6b89b52645243662a02ba734ecf";
+    const OTHER_VERIFIER: &str = "0x1111111111111111111111111111111111111111";
+    const ALLOCATION: &str = "0xab5d9d98b6dbd2e9b7c0e19fb3f2b9d7be0ff5d8";
+    const MAX_AGE: Duration = Duration::from_secs(30);
+    const NOW: u128 = 1_700_000_000_000_000_000;
+
+    fn address(private_key: &str) -> String {
+        format!(
+            "{:#x}",
+            LocalWallet::from_str(private_key).unwrap().address()
+        )
+    }
+
+    async fn sign_receipt(
+        private_key: &str,
+        verifier: &str,
+        timestamp_ns: u64,
+        value: u128,
+    ) -> TapReceipt {
+        let nonce = rand::thread_rng().next_u64();
+        signed_receipt(
+            private_key,
+            CHAIN_ID,
+            verifier,
+            ALLOCATION,
+            timestamp_ns,
+            nonce,
+            value,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_receipt_signature() {
+        let valid = sign_receipt(PRIVATE_KEY, VERIFIER, NOW as u64, 100).await;
+        let signer = check_receipt(&valid, 100, NOW, MAX_AGE, CHAIN_ID, VERIFIER).unwrap();
+        assert_eq!(signer, address(PRIVATE_KEY));
+
+        // Signed by another key, or for another verifier, the receipt is not from the sender
+        let other_key = sign_receipt(OTHER_KEY, VERIFIER, NOW as u64, 100).await;
+        let signer = check_receipt(&other_key, 100, NOW, MAX_AGE, CHAIN_ID, VERIFIER).unwrap();
+        assert_eq!(signer, address(OTHER_KEY));
+        let other_domain = sign_receipt(PRIVATE_KEY, OTHER_VERIFIER, NOW as u64, 100).await;
+        let signer = check_receipt(&other_domain, 100, NOW, MAX_AGE, CHAIN_ID, VERIFIER).unwrap();
+        assert_ne!(signer, address(PRIVATE_KEY));
+        // ...so the escrow account looked up for the recovered signer does not pay for it
+        assert!(matches!(
+            check_escrow(&signer, None, 100, 0),
+            Err(PaymentError::Rejected(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_receipt_value_and_age() {
+        let valid = sign_receipt(PRIVATE_KEY, VERIFIER, NOW as u64, 100).await;
+        assert!(matches!(
+            check_receipt(&valid, 101, NOW, MAX_AGE, CHAIN_ID, VERIFIER),
+            Err(PaymentError::Rejected(_))
+        ));
+
+        let max_age = MAX_AGE.as_nanos();
+        for timestamp in [NOW - max_age - 1, NOW + max_age + 1] {
+            let receipt = sign_receipt(PRIVATE_KEY, VERIFIER, timestamp as u64, 100).await;
+            assert!(matches!(
+                check_receipt(&receipt, 100, NOW, MAX_AGE, CHAIN_ID, VERIFIER),
+                Err(PaymentError::Rejected(_))
+            ));
+        }
+        let receipt = sign_receipt(PRIVATE_KEY, VERIFIER, (NOW - max_age) as u64, 100).await;
+        assert!(check_receipt(&receipt, 100, NOW, MAX_AGE, CHAIN_ID, VERIFIER).is_ok());
+    }
+
+    #[test]
+    fn test_escrow_covers_outstanding() {
+        let sender = address(PRIVATE_KEY);
+        assert!(check_escrow(&sender, Some(300), 100, 200).is_ok());
+        assert!(matches!(
+            check_escrow(&sender, Some(300), 100, 201),
+            Err(PaymentError::Rejected(_))
+        ));
+        assert!(matches!(
+            check_escrow(&sender, None, 100, 0),
+            Err(PaymentError::Rejected(_))
+        ));
+        // Amounts are compared exactly, well past the precision of floats
+        let balance = (1u128 << 64) + 1;
+        assert!(check_escrow(&sender, Some(balance), 1 << 63, 1 << 63).is_ok());
+        assert!(matches!(
+            check_escrow(&sender, Some(balance), (1 << 63) + 1, 1 << 63),
+            Err(PaymentError::Rejected(_))
+        ));
+        assert!(matches!(
+            check_escrow(&sender, Some(u128::MAX), u128::MAX, 1),
+            Err(PaymentError::Rejected(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_receipt_allocation() {
+        let receipt = sign_receipt(PRIVATE_KEY, VERIFIER, NOW as u64, 100).await;
+        let deployment = "QmHash";
+        assert!(check_allocation(&receipt, deployment, &[ALLOCATION.to_string()]).is_ok());
+        assert!(matches!(
+            check_allocation(&receipt, deployment, &[OTHER_VERIFIER.to_string()]),
+            Err(PaymentError::Rejected(_))
+        ));
+        assert!(matches!(
+            check_allocation(&receipt, deployment, &[]),
+            Err(PaymentError::Rejected(_))
+        ));
+    }
+
+    #[test]
+    fn test_free_query_authorization() {
+        let token = Some("free-token");
+        let bearer = json!({"authorization": "Bearer free-token"});
+        assert!(free_query_authorized(&bearer, token));
+        assert!(free_query_authorized(
+            &json!({"authorization": "free-token"}),
+            token
+        ));
+        assert!(!free_query_authorized(
+            &json!({"authorization": "Bearer other"}),
+            token
+        ));
+        assert!(!free_query_authorized(&json!({}), token));
+        // Without a token configured, no request is free
+        assert!(!free_query_authorized(&bearer, None));
+    }
+
+    #[tokio::test]
+    #[ignore] // Run with DATABASE_URL pointing at a database with the migrations applied
+    async fn test_receipt_replay() {
+        let pool = database::connect(&std::env::var("DATABASE_URL").unwrap()).await;
+        let signer = address(PRIVATE_KEY);
+        let signer = signer.trim_start_matches("0x");
+        let numeric = |value: u128| BigDecimal::from_str(&value.to_string()).unwrap();
+
+        let receipt = sign_receipt(PRIVATE_KEY, VERIFIER, NOW as u64, 100).await;
+        assert!(database::store_receipt(&pool, signer, &receipt)
+            .await
+            .unwrap());
+        assert!(!database::store_receipt(&pool, signer, &receipt)
+            .await
+            .unwrap());
+
+        // A receipt already stored from the request header still pays for the request once
+        let header = sign_receipt(PRIVATE_KEY, VERIFIER, NOW as u64, 100).await;
+        let message = &header.message;
+        sqlx::query(
+            r#"INSERT INTO scalar_tap_receipts
+                (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
+            VALUES ($1, $2, $3, $4, $5, $6)"#,
+        )
+        .bind(signer)
+        .bind(header.signature.to_vec())
+        .bind(hex::encode(message.allocation_id))
+        .bind(numeric(message.timestamp_ns as u128))
+        .bind(numeric(message.nonce as u128))
+        .bind(numeric(message.value))
+        .execute(&pool)
+        .await
+        .unwrap();
+        // but not a different receipt reusing its nonce
+        let reused = signed_receipt(
+            PRIVATE_KEY,
+            CHAIN_ID,
+            VERIFIER,
+            ALLOCATION,
+            NOW as u64,
+            message.nonce,
+            200,
+        )
+        .await;
+        assert!(!database::store_receipt(&pool, signer, &reused)
+            .await
+            .unwrap());
+        assert!(database::store_receipt(&pool, signer, &header)
+            .await
+            .unwrap());
+        assert!(!database::store_receipt(&pool, signer, &header)
+            .await
+            .unwrap());
+    }
+}
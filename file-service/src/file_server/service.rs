@@ -3,13 +3,14 @@
 use indexer_common::indexer_service::http::IndexerServiceImpl;
 use thegraph::types::DeploymentId;
 
-use file_exchange::errors::Error;
+use file_exchange::{config::StorageMethod, errors::Error};
 // #![cfg(feature = "acceptor")]
 // use hyper_rustls::TlsAcceptor;
 use hyper::{Body, Response, StatusCode};
 
 use super::{
-    range::{parse_range_header, serve_file, serve_file_range},
+    range::{parse_range_header, range_not_satisfiable, serve_file, serve_file_range},
+    receipt::{verify_payment, PaymentError},
     ServerContext,
 };
 
@@ -34,6 +35,15 @@ pub async fn file_service(
         }
     };
 
+    let store = context.state.stores.bundle_store(&id.to_string()).await;
+    // Local files are served straight from disk, caching only helps remote object storage
+    let chunk_cache = match store.storage_method {
+        StorageMethod::ObjectStorage(_) => context.state.chunk_cache.as_deref(),
+        StorageMethod::LocalFiles(_) => None,
+    };
+
+    let throttle = &context.state.throttle;
+
     match req.get("file-hash") {
         Some(hash) if hash.as_str().is_some() => {
             let file_manifest = match local_bundle
@@ -51,23 +61,55 @@ pub async fn file_service(
                 }
             };
             // Parse the range header to get the start and end bytes
-            match req.get("content-range") {
-                Some(r) => {
-                    let range = parse_range_header(r)?;
-                    //TODO: validate receipt
+            let range = match req.get("content-range") {
+                Some(r) => Some(parse_range_header(r)?),
+                None => None,
+            };
+            // A range outside of the file is refused before its receipt is charged
+            if let Some(response) =
+                range.and_then(|range| range_not_satisfiable(file_manifest, range))
+            {
+                return Ok(response);
+            }
+            // Payments cover the bytes requested at the bundle's price
+            let bytes = match range {
+                Some((start, end)) => (end - start + 1) as u64,
+                None => file_manifest.file_manifest.total_bytes,
+            };
+            let signer = match verify_payment(&context.state, &id.to_string(), req, bytes).await {
+                Ok(signer) => signer,
+                Err(PaymentError::Rejected(msg)) => {
+                    tracing::warn!(reason = msg.as_str(), "Reject payment");
+                    return Ok(Response::builder()
+                        .status(StatusCode::PAYMENT_REQUIRED)
+                        .body(msg.into())
+                        .unwrap());
+                }
+                Err(PaymentError::Unverified(e)) => return Err(e),
+            };
+            // Consumers are told apart by the signer of their receipts, which they cannot
+            // claim freely; free queries share one bandwidth bucket
+            let consumer = signer.as_deref().unwrap_or("free-query");
+            match range {
+                Some(range) => {
                     serve_file_range(
-                        context.state.store.clone(),
-                        &file_manifest.meta_info.name,
+                        store,
+                        chunk_cache,
+                        file_manifest,
                         &local_bundle.local_path,
                         range,
+                        throttle,
+                        consumer,
                     )
                     .await
                 }
                 None => {
                     serve_file(
-                        context.state.store.clone(),
+                        store,
                         &file_manifest.meta_info.name,
                         &local_bundle.local_path,
+                        throttle,
+                        consumer,
                     )
                     .await
                 }
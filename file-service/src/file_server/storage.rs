@@ -0,0 +1,138 @@
+use object_store::path::Path;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use file_exchange::{
+    config::{LocalDirectory, ObjectStoreArgs, StorageMethod, StoreOptions},
+    errors::Error,
+    manifest::store::Store,
+};
+
+use crate::config::{ServerArgs, StorageProfile};
+
+/// Storage backends of served bundles. Bundles located by a plain prefix are served from
+/// the main storage; bundles located by a storage spec share a store per backend.
+#[derive(Debug, Clone)]
+pub struct BundleStores {
+    main: Store,
+    local_options: StoreOptions,
+    profiles: HashMap<String, StorageProfile>,
+    backends: Arc<Mutex<HashMap<String, Store>>>, // Keyed by backend spec
+    bundles: Arc<Mutex<HashMap<String, Store>>>,  // Keyed by bundle IPFS hash
+}
+
+impl BundleStores {
+    pub fn new(config: &ServerArgs) -> Result<Self, Error> {
+        Ok(BundleStores {
+            main: Store::new(&config.storage_method)?,
+            local_options: config.storage_method.store_options().clone(),
+            profiles: config.storage_profiles.clone(),
+            backends: Arc::new(Mutex::new(HashMap::new())),
+            bundles: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Resolve a bundle location into its store and the prefix of the bundle files in it,
+    /// creating the backend store on first use
+    pub async fn resolve(&self, location: &str) -> Result<(Store, Path), Error> {
+        let Some((backend, storage_method, prefix)) = self.parse_location(location)? else {
+            return Ok((self.main.clone(), Path::from(location)));
+        };
+
+        let mut backends = self.backends.lock().await;
+        if let Some(store) = backends.get(&backend) {
+            return Ok((store.clone(), prefix));
+        }
+        tracing::info!(backend, "Add storage backend");
+        let store = Store::new(&storage_method)?;
+        backends.insert(backend, store.clone());
+        Ok((store, prefix))
+    }
+
+    /// Parse a storage spec into a backend key, its storage method and the prefix within;
+    /// `None` for a plain prefix in the main storage
+    fn parse_location(
+        &self,
+        location: &str,
+    ) -> Result<Option<(String, StorageMethod, Path)>, Error> {
+        let Some((scheme, rest)) = location.split_once("://") else {
+            return Ok(None);
+        };
+
+        match scheme {
+            "file" => Ok(Some((
+                location.to_string(),
+                StorageMethod::LocalFiles(LocalDirectory {
+                    main_dir: rest.to_string(),
+                    store_options: self.local_options.clone(),
+                }),
+                Path::from(""),
+            ))),
+            "s3" => {
+                let (bucket_path, query) = rest.split_once('?').unwrap_or((rest, ""));
+                let profile_name = query
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("profile="))
+                    .ok_or(Error::InvalidConfig(format!(
+                        "Missing storage profile in location: {}",
+                        location
+                    )))?;
+                let profile =
+                    self.profiles
+                        .get(profile_name)
+                        .ok_or(Error::InvalidConfig(format!(
+                            "Unknown storage profile: {}",
+                            profile_name
+                        )))?;
+                let (bucket, prefix) = bucket_path.split_once('/').unwrap_or((bucket_path, ""));
+                if bucket.is_empty() {
+                    return Err(Error::InvalidConfig(format!(
+                        "Missing bucket in location: {}",
+                        location
+                    )));
+                }
+
+                Ok(Some((
+                    format!("s3://{}?profile={}", bucket, profile_name),
+                    StorageMethod::ObjectStorage(ObjectStoreArgs {
+                        region: profile.region.clone(),
+                        bucket: bucket.to_string(),
+                        access_key_id: profile.access_key_id.clone(),
+                        secret_key: profile.secret_key.clone(),
+                        endpoint: profile.endpoint.clone(),
+                        store_options: profile.store_options.clone(),
+                    }),
+                    Path::from(prefix),
+                )))
+            }
+            _ => Err(Error::InvalidConfig(format!(
+                "Unsupported storage scheme: {}",
+                scheme
+            ))),
+        }
+    }
+
+    /// Record the store serving a bundle
+    pub async fn assign(&self, bundle_hash: &str, store: Store) {
+        self.bundles
+            .lock()
+            .await
+            .insert(bundle_hash.to_string(), store);
+    }
+
+    /// Forget the store of a bundle no longer served
+    pub async fn unassign(&self, bundle_hash: &str) {
+        self.bundles.lock().await.remove(bundle_hash);
+    }
+
+    /// Store serving a bundle, the main storage if none was assigned
+    pub async fn bundle_store(&self, bundle_hash: &str) -> Store {
+        self.bundles
+            .lock()
+            .await
+            .get(bundle_hash)
+            .cloned()
+            .unwrap_or_else(|| self.main.clone())
+    }
+}
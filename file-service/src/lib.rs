@@ -3,3 +3,4 @@ pub mod config;
 pub mod database;
 pub mod file_server;
 pub mod metrics;
+pub mod scrubber;
@@ -5,7 +5,7 @@ use axum::Router;
 use axum::Server;
 use once_cell::sync::Lazy;
 use prometheus::{core::Collector, Registry};
-use prometheus::{HistogramOpts, HistogramVec, IntGaugeVec, Opts};
+use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts};
 
 use crate::config::ServerArgs;
 
@@ -43,6 +43,51 @@ pub static TRANSFERRED_BYTES: Lazy<IntGaugeVec> = Lazy::new(|| {
     m
 });
 
+// Chunk cache hits by tier
+pub static CHUNK_CACHE_HITS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new(
+            "chunk_cache_hits",
+            "Number of chunk requests served from cache",
+        )
+        .namespace("file_service"),
+        &["tier"],
+    )
+    .expect("Failed to create chunk_cache_hits counter");
+    prometheus::register(Box::new(m.clone())).expect("Failed to register chunk_cache_hits counter");
+    m
+});
+
+// Chunk cache misses falling through to the store
+pub static CHUNK_CACHE_MISSES: Lazy<IntCounter> = Lazy::new(|| {
+    let m = IntCounter::with_opts(
+        Opts::new(
+            "chunk_cache_misses",
+            "Number of chunk requests read from the store",
+        )
+        .namespace("file_service"),
+    )
+    .expect("Failed to create chunk_cache_misses counter");
+    prometheus::register(Box::new(m.clone()))
+        .expect("Failed to register chunk_cache_misses counter");
+    m
+});
+
+// Bytes currently held by each chunk cache tier
+pub static CHUNK_CACHE_BYTES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    let m = IntGaugeVec::new(
+        Opts::new(
+            "chunk_cache_bytes",
+            "Number of bytes held in the chunk cache",
+        )
+        .namespace("file_service"),
+        &["tier"],
+    )
+    .expect("Failed to create chunk_cache_bytes gauge");
+    prometheus::register(Box::new(m.clone())).expect("Failed to register chunk_cache_bytes gauge");
+    m
+});
+
 #[allow(dead_code)]
 pub static REGISTRY: Lazy<prometheus::Registry> = Lazy::new(prometheus::Registry::new);
 
@@ -61,6 +106,9 @@ pub fn start_metrics() {
         vec![
             Box::new(RESPONSE_TIME.clone()),
             Box::new(TRANSFERRED_BYTES.clone()),
+            Box::new(CHUNK_CACHE_HITS.clone()),
+            Box::new(CHUNK_CACHE_MISSES.clone()),
+            Box::new(CHUNK_CACHE_BYTES.clone()),
         ],
     );
 }
@@ -0,0 +1,290 @@
+use object_store::path::Path;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use file_exchange::{
+    errors::Error,
+    manifest::{file_hasher::verify_chunk, store::Store, FileManifestMeta, LocalBundle},
+};
+
+use crate::{database, file_server::ServerContext};
+
+/// Periodically re-verify served files against their chunk hashes. A bundle with a file
+/// failing verification is moved out of service so it is no longer advertised or served;
+/// files that cannot be read are retried on the next pass.
+pub fn serve_scrubber(context: ServerContext) {
+    let interval = context.state.config.server.scrub_interval;
+    if interval == 0 {
+        tracing::info!("Background scrubbing disabled");
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            scrub_bundles(&context).await;
+        }
+    });
+}
+
+/// Scrub all bundles currently in service
+pub async fn scrub_bundles(context: &ServerContext) {
+    let state = &context.state;
+    let bundles: Vec<LocalBundle> = state.bundles.lock().await.values().cloned().collect();
+    tracing::debug!(bundles = bundles.len(), "Start scrubbing served bundles");
+
+    for local in bundles {
+        let bundle_hash = local.bundle.ipfs_hash.clone();
+        let store = state.stores.bundle_store(&bundle_hash).await;
+        for file_meta in &local.bundle.file_manifests {
+            let file_hash = &file_meta.meta_info.hash;
+            let result = scrub_file(
+                &store,
+                file_meta,
+                &local.local_path,
+                state.config.server.scrub_bandwidth,
+            )
+            .await;
+
+            // Errors reading a file say nothing about its data, so they leave the result
+            // inconclusive and the file is scrubbed again on the next pass
+            let passed = match &result {
+                Ok(()) => Some(true),
+                Err(e) if is_integrity_failure(e) => Some(false),
+                Err(_) => None,
+            };
+            if let Err(e) = database::record_scrub_result(
+                &state.database,
+                &bundle_hash,
+                file_hash,
+                passed,
+                result.as_ref().err().map(|e| e.to_string()),
+            )
+            .await
+            {
+                tracing::warn!(error = e.to_string(), "Failed to record scrub result");
+            }
+
+            match result {
+                Ok(()) => {
+                    if let Ok(object) = store
+                        .object_meta(&file_meta.meta_info.name, &local.local_path)
+                        .await
+                    {
+                        let _ = database::record_verification(
+                            &state.database,
+                            &bundle_hash,
+                            file_hash,
+                            &object,
+                        )
+                        .await;
+                    }
+                }
+                Err(e) => {
+                    let quarantined = handle_scrub_failure(
+                        &state.bundles,
+                        &state.quarantined_bundles,
+                        &bundle_hash,
+                        file_hash,
+                        &e,
+                    )
+                    .await;
+                    if quarantined {
+                        let _ = database::clear_verifications(&state.database, &bundle_hash).await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    tracing::debug!("Finished scrubbing served bundles");
+}
+
+/// Whether a scrub error shows the data of a file is corrupt or missing, rather than that the
+/// file could not be read this time
+fn is_integrity_failure(error: &Error) -> bool {
+    matches!(error, Error::ChunkInvalid(_) | Error::DataUnavailable(_))
+}
+
+/// Take a bundle out of service when a file of it is corrupt or missing. Other errors, such as
+/// transient storage failures, are logged and leave the bundle in service. Returns whether the
+/// bundle was taken out of service.
+async fn handle_scrub_failure(
+    bundles: &Mutex<HashMap<String, LocalBundle>>,
+    quarantined_bundles: &Mutex<HashMap<String, LocalBundle>>,
+    bundle_hash: &str,
+    file_hash: &str,
+    error: &Error,
+) -> bool {
+    if !is_integrity_failure(error) {
+        tracing::warn!(
+            bundle_hash,
+            file_hash,
+            error = error.to_string(),
+            "Scrub inconclusive, retry on the next pass"
+        );
+        return false;
+    }
+    tracing::error!(
+        bundle_hash,
+        file_hash,
+        error = error.to_string(),
+        "Scrub failed, stop serving bundle"
+    );
+    if let Some(removed) = bundles.lock().await.remove(bundle_hash) {
+        quarantined_bundles
+            .lock()
+            .await
+            .insert(bundle_hash.to_string(), removed);
+    }
+    true
+}
+
+/// Read and verify every chunk of a file under the bundle prefix, pacing reads to stay within
+/// `bandwidth` bytes per second. A file missing from storage fails the scrub.
+pub async fn scrub_file(
+    store: &Store,
+    file_meta: &FileManifestMeta,
+    prefix: &Path,
+    bandwidth: u64,
+) -> Result<(), Error> {
+    let file_name = &file_meta.meta_info.name;
+    let file_manifest = &file_meta.file_manifest;
+    let started = Instant::now();
+    let mut bytes_read: u64 = 0;
+
+    for i in 0..(file_manifest.total_bytes / file_manifest.chunk_size + 1) {
+        let start = i * file_manifest.chunk_size;
+        let end = u64::min(start + file_manifest.chunk_size, file_manifest.total_bytes);
+        let range = std::ops::Range {
+            start: start as usize,
+            end: end as usize,
+        };
+        let chunk_data = match store.range_read(file_name, prefix, &range).await {
+            Ok(data) => data,
+            Err(Error::ObjectStoreError(object_store::Error::NotFound { .. })) => {
+                return Err(Error::DataUnavailable(format!(
+                    "File {} is missing from storage",
+                    file_meta.meta_info.hash
+                )))
+            }
+            Err(e) => return Err(e),
+        };
+        if !verify_chunk(&chunk_data, &file_manifest.chunk_hashes[i as usize]) {
+            return Err(Error::ChunkInvalid(format!(
+                "Chunk {} of file {} does not match its hash",
+                i, file_meta.meta_info.hash
+            )));
+        }
+
+        // Sleep off any time ahead of the bandwidth budget
+        bytes_read += chunk_data.len() as u64;
+        if bandwidth > 0 {
+            let budgeted = Duration::from_secs_f64(bytes_read as f64 / bandwidth as f64);
+            if let Some(ahead) = budgeted.checked_sub(started.elapsed()) {
+                tokio::time::sleep(ahead).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use file_exchange::manifest::{file_hasher::hash_chunk, FileManifest, FileMetaInfo};
+    use file_exchange::{
+        config::{LocalDirectory, StorageMethod},
+        test_util::{random_bytes, simple_bundle},
+    };
+
+    const CHUNK_SIZE: usize = 4;
+
+    fn local_store(dir: &tempfile::TempDir) -> Store {
+        Store::new(&StorageMethod::LocalFiles(LocalDirectory {
+            main_dir: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        }))
+        .unwrap()
+    }
+
+    fn file_meta(name: &str, bytes: &[u8]) -> FileManifestMeta {
+        FileManifestMeta {
+            meta_info: FileMetaInfo {
+                name: name.to_string(),
+                hash: "QmFile".to_string(),
+            },
+            file_manifest: FileManifest {
+                total_bytes: bytes.len() as u64,
+                chunk_size: CHUNK_SIZE as u64,
+                chunk_hashes: bytes.chunks(CHUNK_SIZE).map(hash_chunk).collect(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scrub_file_under_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = local_store(&dir);
+        let bytes = random_bytes(CHUNK_SIZE * 2 + 2);
+        store.write("bundle/data.bin", &bytes).await.unwrap();
+        let meta = file_meta("data.bin", &bytes);
+
+        let prefix = Path::from("bundle");
+        assert!(scrub_file(&store, &meta, &prefix, 0).await.is_ok());
+        // The file is not at the store root
+        assert!(scrub_file(&store, &meta, &Path::from(""), 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scrub_corrupted_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = local_store(&dir);
+        let mut bytes = random_bytes(CHUNK_SIZE * 2 + 2);
+        let meta = file_meta("data.bin", &bytes);
+        bytes[CHUNK_SIZE] = bytes[CHUNK_SIZE].wrapping_add(1);
+        store.write("data.bin", &bytes).await.unwrap();
+
+        let res = scrub_file(&store, &meta, &Path::from(""), 0).await;
+        assert!(matches!(res, Err(Error::ChunkInvalid(_))));
+    }
+
+    #[tokio::test]
+    async fn test_scrub_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = local_store(&dir);
+        let meta = file_meta("data.bin", &random_bytes(CHUNK_SIZE + 2));
+
+        let res = scrub_file(&store, &meta, &Path::from(""), 0).await;
+        assert!(matches!(res, Err(Error::DataUnavailable(_))));
+    }
+
+    #[tokio::test]
+    async fn test_transient_error_keeps_bundle() {
+        let bundle = simple_bundle();
+        let bundle_hash = bundle.ipfs_hash.clone();
+        let local = LocalBundle {
+            bundle,
+            local_path: Path::from(""),
+        };
+        let bundles = Mutex::new(HashMap::from([(bundle_hash.clone(), local)]));
+        let quarantined = Mutex::new(HashMap::new());
+
+        let transient = Error::ObjectStoreError(object_store::Error::Generic {
+            store: "S3",
+            source: "connection reset".into(),
+        });
+        let removed =
+            handle_scrub_failure(&bundles, &quarantined, &bundle_hash, "QmFile", &transient).await;
+        assert!(!removed);
+        assert!(bundles.lock().await.contains_key(&bundle_hash));
+        assert!(quarantined.lock().await.is_empty());
+
+        let corrupt = Error::ChunkInvalid("Chunk 0 does not match its hash".to_string());
+        let removed =
+            handle_scrub_failure(&bundles, &quarantined, &bundle_hash, "QmFile", &corrupt).await;
+        assert!(removed);
+        assert!(bundles.lock().await.is_empty());
+        assert!(quarantined.lock().await.contains_key(&bundle_hash));
+    }
+}